@@ -1,9 +1,22 @@
 use super::{Error, Result};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 const DEFAULT_CONFIG_FILE_NAME: &'static str = "buildbox.toml";
 
+/// Path segments that mark where the instance name ends in a resource name,
+/// so they can't be part of an instance name.
+const RESERVED_INSTANCE_SEGMENTS: [&str; 7] = [
+    "blobs",
+    "compressed-blobs",
+    "uploads",
+    "actions",
+    "actionResults",
+    "operations",
+    "capabilities",
+];
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct Config {
     /// Address for the server to listen on.
@@ -18,6 +31,134 @@ pub struct Config {
     /// Whether to retain sandboxes after use
     #[serde(default)]
     pub retain_sandboxes: bool,
+
+    /// Whether input trees may contain symlinks with absolute targets. They
+    /// point outside of the sandbox, so actions that use them aren't hermetic.
+    #[serde(default)]
+    pub allow_absolute_symlinks: bool,
+
+    /// How long an interrupted upload is kept for the client to resume it.
+    #[serde(default = "default_upload_timeout_secs")]
+    pub upload_timeout_secs: u64,
+
+    /// Size of the chunks that blobs are streamed to clients in.
+    #[serde(default = "default_read_chunk_size_bytes")]
+    pub read_chunk_size_bytes: usize,
+
+    /// Where blobs are stored.
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
+
+    /// Size of an in-memory cache of small blobs, in front of the storage
+    /// backend. There's no cache unless this is set.
+    #[serde(default)]
+    pub memory_cache_bytes: Option<u64>,
+
+    /// Largest blob that is kept in the memory cache. Bigger blobs are always
+    /// read from the storage backend, so they don't push the small ones out.
+    #[serde(default = "default_memory_cache_max_blob_bytes")]
+    pub memory_cache_max_blob_bytes: u64,
+
+    /// Whether large blobs are split into content-defined chunks, so that
+    /// blobs that only differ slightly share most of their storage.
+    #[serde(default)]
+    pub storage_chunking: bool,
+
+    /// How blobs are compressed in the storage directory.
+    #[serde(default)]
+    pub storage_compression: Compression,
+
+    /// Largest total size of the blobs in the storage directory. The least
+    /// recently used blobs are evicted to stay under it.
+    #[serde(default)]
+    pub max_storage_bytes: Option<u64>,
+
+    /// Free disk space to keep on the filesystem of the storage directory, by
+    /// evicting the least recently used blobs.
+    #[serde(default)]
+    pub min_free_disk_bytes: Option<u64>,
+
+    /// How often the storage limits are enforced.
+    #[serde(default = "default_gc_interval_secs")]
+    pub gc_interval_secs: u64,
+
+    /// Whether to check that blobs still match their digest whenever they're
+    /// read, so that corrupted blobs are never served.
+    #[serde(default)]
+    pub verify_reads: bool,
+
+    /// Whether stored blobs are flushed to disk before their upload completes,
+    /// so that they survive a crash. Turning this off trades durability for
+    /// faster uploads.
+    #[serde(default = "default_sync_writes")]
+    pub sync_writes: bool,
+
+    /// Settings of the instances that clients can pick with an instance name.
+    /// Every instance has an action cache of its own. As long as there are
+    /// none, every instance name is served by the default instance.
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceConfig>,
+}
+
+/// Settings of an instance, in the `[instances."<name>"]` section. Anything
+/// that isn't set is shared with the default instance.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct InstanceConfig {
+    /// Execution directory of the instance.
+    #[serde(default)]
+    pub sandbox_dir: Option<String>,
+
+    /// Whether clients are refused when they try to update the action cache.
+    /// The results of actions executed by the server are still cached.
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Whether the instance keeps its blobs apart from other instances,
+    /// rather than sharing the storage of the default instance.
+    #[serde(default)]
+    pub separate_storage: bool,
+}
+
+/// Where blobs are stored.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Blobs are stored as files in the storage directory.
+    #[default]
+    File,
+    /// Blobs are only kept in memory, and are lost when the server stops.
+    Memory,
+}
+
+/// Compression applied to blobs at rest.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Blobs are stored as they are.
+    #[default]
+    None,
+    /// Blobs are compressed with zstd, unless they don't compress well.
+    Zstd,
+}
+
+fn default_upload_timeout_secs() -> u64 {
+    600
+}
+
+fn default_read_chunk_size_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_memory_cache_max_blob_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_gc_interval_secs() -> u64 {
+    60
+}
+
+fn default_sync_writes() -> bool {
+    true
 }
 
 impl Config {
@@ -43,9 +184,34 @@ impl Config {
         tracing::info!("Using storage directory: {storage_dir}");
         tracing::info!("Using sandbox directory: {sandbox_dir}");
 
+        for (name, instance) in &mut config.instances {
+            check_instance_name(name)?;
+
+            if let Some(dir) = &instance.sandbox_dir {
+                let dir = shellexpand::tilde(dir).to_string();
+                std::fs::create_dir_all(&dir).map_err(Error::io)?;
+                tracing::info!("Using sandbox directory for instance {name}: {dir}");
+                instance.sandbox_dir = Some(dir);
+            }
+        }
+
         Ok(config)
     }
 
+    /// Directory that holds the stores of the instance called `name`.
+    pub fn instance_dir(&self, name: &str) -> PathBuf {
+        Path::new(&self.storage_dir).join("instances").join(name)
+    }
+
+    /// Directory that the instance called `name` keeps its blobs in, if it
+    /// doesn't share the storage of the default instance.
+    pub fn instance_storage_dir(&self, name: &str) -> Option<PathBuf> {
+        let instance = self.instances.get(name)?;
+        instance
+            .separate_storage
+            .then(|| self.instance_dir(name).join("cas"))
+    }
+
     fn load_from_path(path: &PathBuf) -> Result<Config> {
         let content = std::fs::read_to_string(path).map_err(Error::io)?;
         toml::from_str(&content).map_err(Error::boxed)
@@ -57,6 +223,41 @@ impl Config {
             storage_dir: "~/.buildbox/storage".to_string(),
             sandbox_dir: "~/.buildbox/sandbox".to_string(),
             retain_sandboxes: false,
+            allow_absolute_symlinks: false,
+            upload_timeout_secs: default_upload_timeout_secs(),
+            read_chunk_size_bytes: default_read_chunk_size_bytes(),
+            storage_backend: StorageBackend::default(),
+            memory_cache_bytes: None,
+            memory_cache_max_blob_bytes: default_memory_cache_max_blob_bytes(),
+            storage_chunking: false,
+            storage_compression: Compression::default(),
+            max_storage_bytes: None,
+            min_free_disk_bytes: None,
+            gc_interval_secs: default_gc_interval_secs(),
+            verify_reads: false,
+            sync_writes: default_sync_writes(),
+            instances: BTreeMap::new(),
         }
     }
+}
+
+/// Check that an instance name can be told apart from the rest of a resource
+/// name, and can be used as a path within the storage directory. The default
+/// instance, whose name is empty, is configured at the top level instead.
+fn check_instance_name(name: &str) -> Result<()> {
+    let valid = name.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && !RESERVED_INSTANCE_SEGMENTS.contains(&segment)
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+    });
+
+    if !valid {
+        return Err(Error::invalid(&format!("invalid instance name: {name:?}")));
+    }
+
+    Ok(())
 }
\ No newline at end of file
@@ -7,8 +7,10 @@ pub enum Error {
     Runtime(String),
     NotFound(String),
     InvalidArgument(String),
+    FailedPrecondition(String),
+    PermissionDenied(String),
     Io(Option<String>, std::io::Error),
-    Boxed(Option<String>, Box<dyn std::error::Error>),
+    Boxed(Option<String>, Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl Error {
@@ -27,6 +29,16 @@ impl Error {
         Error::InvalidArgument(msg.to_string())
     }
 
+    #[must_use]
+    pub fn failed_precondition(msg: &str) -> Error {
+        Error::FailedPrecondition(msg.to_string())
+    }
+
+    #[must_use]
+    pub fn permission_denied(msg: &str) -> Error {
+        Error::PermissionDenied(msg.to_string())
+    }
+
     #[must_use]
     pub fn io(err: std::io::Error) -> Error {
         Error::Io(None, err)
@@ -38,12 +50,15 @@ impl Error {
     }
 
     #[must_use]
-    pub fn boxed(err: impl std::error::Error + 'static) -> Error {
+    pub fn boxed(err: impl std::error::Error + Send + Sync + 'static) -> Error {
         Error::Boxed(None, Box::new(err))
     }
 
     #[must_use]
-    pub fn boxed_msg<E: std::error::Error + 'static>(msg: &str) -> impl Fn(E) -> Error + '_ {
+    pub fn boxed_msg<E>(msg: &str) -> impl Fn(E) -> Error + '_
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
         |err| Error::Boxed(Some(msg.to_owned()), Box::new(err))
     }
 }
@@ -56,6 +71,8 @@ impl fmt::Display for Error {
             Error::Runtime(msg) => write!(f, "runtime error: {msg}"),
             Error::NotFound(msg) => write!(f, "not found: {msg}"),
             Error::InvalidArgument(msg) => write!(f, "invalid argument: {msg}"),
+            Error::FailedPrecondition(msg) => write!(f, "failed precondition: {msg}"),
+            Error::PermissionDenied(msg) => write!(f, "permission denied: {msg}"),
             Error::Io(msg, err) => write!(f, "io error: {}{err}", format_msg(msg)),
             Error::Boxed(msg, err) => write!(f, "boxed error: {}{err}", format_msg(msg)),
         }
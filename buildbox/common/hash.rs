@@ -1,34 +1,173 @@
-use ring::digest::{Context, SHA256};
+use crate::{Error, Result};
+use proto::bazel::exec::digest_function;
+use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA384, SHA512};
+use std::fmt;
 use std::io::Write;
 
 pub fn sha256(data: &[u8]) -> Digest {
-    let mut hasher = Hasher::sha256();
-    hasher.write(data).expect("failed to write to SHA256 hasher");
+    compute(DigestFunction::Sha256, data)
+}
+
+/// Hash `data` with `function`.
+pub fn compute(function: DigestFunction, data: &[u8]) -> Digest {
+    let mut hasher = Hasher::new(function);
+    hasher.write_all(data).expect("failed to write to hasher");
     hasher.finish()
 }
 
+/// A hash function that blobs can be addressed by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DigestFunction {
+    Sha1,
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+    Blake3,
+}
+
+impl DigestFunction {
+    /// Every supported digest function.
+    pub const ALL: [DigestFunction; 5] = [
+        DigestFunction::Sha1,
+        DigestFunction::Sha256,
+        DigestFunction::Sha384,
+        DigestFunction::Sha512,
+        DigestFunction::Blake3,
+    ];
+
+    /// Lowercase name of the function, as used in resource names.
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestFunction::Sha1 => "sha1",
+            DigestFunction::Sha256 => "sha256",
+            DigestFunction::Sha384 => "sha384",
+            DigestFunction::Sha512 => "sha512",
+            DigestFunction::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|function| function.name() == name)
+    }
+
+    /// Length of a hash, in hex.
+    fn hex_len(self) -> usize {
+        match self {
+            DigestFunction::Sha1 => 40,
+            DigestFunction::Sha256 | DigestFunction::Blake3 => 64,
+            DigestFunction::Sha384 => 96,
+            DigestFunction::Sha512 => 128,
+        }
+    }
+
+    /// Work out the function of a hash from its length, for clients that
+    /// don't say which one they used. Only the functions that predate the
+    /// protocol naming them can be inferred, so a hash of 64 characters is
+    /// always SHA256 rather than BLAKE3.
+    pub fn infer(hash: &str) -> Result<Self> {
+        [Self::Sha1, Self::Sha256, Self::Sha384, Self::Sha512]
+            .into_iter()
+            .find(|function| function.hex_len() == hash.len())
+            .ok_or_else(|| Error::invalid(&format!("unknown digest function for {hash:?}")))
+    }
+
+    /// The function named by the `digest_function` field of a request, or the
+    /// one inferred from `hash` if the field isn't set.
+    pub fn from_request(value: i32, hash: &str) -> Result<Self> {
+        match digest_function::Value::try_from(value) {
+            Ok(digest_function::Value::Unknown) => Self::infer(hash),
+            Ok(value) => Self::try_from(value),
+            Err(_) => Err(Error::invalid(&format!("unknown digest function {value}"))),
+        }
+    }
+}
+
+impl From<DigestFunction> for digest_function::Value {
+    fn from(function: DigestFunction) -> Self {
+        match function {
+            DigestFunction::Sha1 => digest_function::Value::Sha1,
+            DigestFunction::Sha256 => digest_function::Value::Sha256,
+            DigestFunction::Sha384 => digest_function::Value::Sha384,
+            DigestFunction::Sha512 => digest_function::Value::Sha512,
+            DigestFunction::Blake3 => digest_function::Value::Blake3,
+        }
+    }
+}
+
+impl TryFrom<digest_function::Value> for DigestFunction {
+    type Error = Error;
+
+    fn try_from(value: digest_function::Value) -> Result<Self> {
+        match value {
+            digest_function::Value::Sha1 => Ok(DigestFunction::Sha1),
+            digest_function::Value::Sha256 => Ok(DigestFunction::Sha256),
+            digest_function::Value::Sha384 => Ok(DigestFunction::Sha384),
+            digest_function::Value::Sha512 => Ok(DigestFunction::Sha512),
+            digest_function::Value::Blake3 => Ok(DigestFunction::Blake3),
+            value => Err(Error::invalid(&format!(
+                "unsupported digest function {}",
+                value.as_str_name()
+            ))),
+        }
+    }
+}
+
 pub struct Hasher {
-    ctx: Context,
+    function: DigestFunction,
+    state: HasherState,
+}
+
+enum HasherState {
+    Ring(Box<Context>),
+    Blake3(Box<blake3::Hasher>),
 }
 
 impl Hasher {
+    /// Create a new [`Hasher`] that constructs hashes with `function`.
+    pub fn new(function: DigestFunction) -> Self {
+        let state = match function {
+            DigestFunction::Sha1 => {
+                HasherState::Ring(Box::new(Context::new(&SHA1_FOR_LEGACY_USE_ONLY)))
+            }
+            DigestFunction::Sha256 => HasherState::Ring(Box::new(Context::new(&SHA256))),
+            DigestFunction::Sha384 => HasherState::Ring(Box::new(Context::new(&SHA384))),
+            DigestFunction::Sha512 => HasherState::Ring(Box::new(Context::new(&SHA512))),
+            DigestFunction::Blake3 => HasherState::Blake3(Box::default()),
+        };
+
+        Self { function, state }
+    }
+
     /// Create a new [`Hasher`] that constructs SHA256 hashes.
     pub fn sha256() -> Self {
-        Self {
-            ctx: Context::new(&SHA256),
-        }
+        Self::new(DigestFunction::Sha256)
     }
 
     /// Finish the hash operation and consume the hasher.
     pub fn finish(self) -> Digest {
-        let digest = self.ctx.finish();
-        Digest::sha256(digest.as_ref())
+        let hash = match self.state {
+            HasherState::Ring(ctx) => data_encoding::HEXLOWER.encode(ctx.finish().as_ref()),
+            HasherState::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        };
+
+        Digest {
+            function: self.function,
+            hash,
+        }
     }
 }
 
 impl Write for Hasher {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.ctx.update(buf);
+        match &mut self.state {
+            HasherState::Ring(ctx) => ctx.update(buf),
+            HasherState::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
         Ok(buf.len())
     }
 
@@ -37,22 +176,182 @@ impl Write for Hasher {
     }
 }
 
-#[derive(Debug)]
-pub enum Digest {
-    Sha256(String),
+/// A hash, along with the function that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    function: DigestFunction,
+    hash: String,
 }
 
 impl Digest {
-    pub fn sha256(digest: &[u8]) -> Self {
-        let base64 = data_encoding::HEXLOWER.encode(digest);
-        Self::Sha256(base64)
+    /// Parse a hash made with `function`, which must be lowercase hex.
+    pub fn parse(function: DigestFunction, hash: &str) -> Result<Self> {
+        let is_hex = hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if hash.len() != function.hex_len() || !is_hex {
+            return Err(Error::invalid(&format!(
+                "invalid {} hash: {hash:?}",
+                function.name()
+            )));
+        }
+
+        Ok(Self {
+            function,
+            hash: hash.to_owned(),
+        })
+    }
+
+    /// Parse the name that a blob with this digest is stored under.
+    pub fn from_blob_name(name: &str) -> Result<Self> {
+        match name.split_once('-') {
+            Some((hash, function)) => {
+                let function = DigestFunction::from_name(function)
+                    .filter(|function| *function != DigestFunction::Sha256)
+                    .ok_or_else(|| Error::invalid(&format!("invalid blob name: {name:?}")))?;
+                Self::parse(function, hash)
+            }
+            None => Self::parse(DigestFunction::Sha256, name),
+        }
+    }
+
+    /// Name that a blob with this digest is stored under. Hashes from
+    /// different functions can have the same length, so all but SHA256 are
+    /// suffixed with the name of their function to keep them apart.
+    pub fn blob_name(&self) -> String {
+        match self.function {
+            DigestFunction::Sha256 => self.hash.clone(),
+            function => format!("{}-{}", self.hash, function.name()),
+        }
+    }
+
+    pub fn function(&self) -> DigestFunction {
+        self.function
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.hash
     }
 }
 
-impl ToString for Digest {
-    fn to_string(&self) -> String {
-        match self {
-            Digest::Sha256(sha256) => sha256.to_owned(),
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.hash)
+    }
+}
+
+/// The digest of a blob, checked to be well-formed. Blobs are stored under
+/// their hash, so a digest that comes from a client has to be checked before
+/// it's used to find one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobDigest {
+    hash: Digest,
+    size_bytes: u64,
+}
+
+impl BlobDigest {
+    /// Check that `hash` is a valid hash for `function` and that `size_bytes`
+    /// isn't negative.
+    pub fn new(function: DigestFunction, hash: &str, size_bytes: i64) -> Result<Self> {
+        let hash = Digest::parse(function, hash)?;
+        let size_bytes = u64::try_from(size_bytes)
+            .map_err(|_| Error::invalid(&format!("invalid blob size: {size_bytes}")))?;
+
+        Ok(Self { hash, size_bytes })
+    }
+
+    /// Check a digest that was computed with `function`.
+    pub fn from_proto(
+        function: DigestFunction,
+        digest: &proto::bazel::exec::Digest,
+    ) -> Result<Self> {
+        Self::new(function, &digest.hash, digest.size_bytes)
+    }
+
+    pub fn hash(&self) -> &str {
+        self.hash.as_str()
+    }
+
+    pub fn function(&self) -> DigestFunction {
+        self.hash.function()
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    /// Name that the blob is stored under.
+    pub fn blob_name(&self) -> String {
+        self.hash.blob_name()
+    }
+}
+
+impl From<BlobDigest> for proto::bazel::exec::Digest {
+    fn from(digest: BlobDigest) -> Self {
+        Self {
+            size_bytes: digest.size_bytes as i64,
+            hash: digest.hash.hash,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blob_digest() {
+        let hash = sha256(b"foo").to_string();
+        let digest = BlobDigest::new(DigestFunction::Sha256, &hash, 3).unwrap();
+        assert_eq!(digest.hash(), hash);
+        assert_eq!(digest.size_bytes(), 3);
+
+        let new = |hash: &str, size| BlobDigest::new(DigestFunction::Sha256, hash, size);
+        assert!(new(&hash, -1).is_err());
+        assert!(new(&hash.to_uppercase(), 3).is_err());
+        assert!(new(&hash[1..], 3).is_err());
+        assert!(new("../../../../etc/passwd", 3).is_err());
+        assert!(new(&format!("../{}", &hash[3..]), 3).is_err());
+    }
+
+    #[test]
+    fn test_digest_functions() {
+        let hashes = [
+            (
+                DigestFunction::Sha1,
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+            (
+                DigestFunction::Sha256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                DigestFunction::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ];
+
+        for (function, hash) in hashes {
+            let digest = compute(function, b"abc");
+            assert_eq!(digest.to_string(), hash);
+            assert_eq!(Digest::parse(function, hash).unwrap(), digest);
+            assert_eq!(Digest::from_blob_name(&digest.blob_name()).unwrap(), digest);
+        }
+
+        assert_eq!(compute(DigestFunction::Sha384, b"abc").as_str().len(), 96);
+        assert_eq!(compute(DigestFunction::Sha512, b"abc").as_str().len(), 128);
+
+        // BLAKE3 and SHA256 hashes have the same length, but are stored apart.
+        let sha256 = compute(DigestFunction::Sha256, b"abc");
+        let blake3 = Digest::parse(DigestFunction::Blake3, sha256.as_str()).unwrap();
+        assert_ne!(sha256.blob_name(), blake3.blob_name());
+
+        assert_eq!(
+            DigestFunction::infer(sha256.as_str()).unwrap(),
+            DigestFunction::Sha256
+        );
+        assert!(DigestFunction::from_request(3, sha256.as_str()).is_err());
+        assert_eq!(
+            DigestFunction::from_request(9, sha256.as_str()).unwrap(),
+            DigestFunction::Blake3
+        );
+    }
+}
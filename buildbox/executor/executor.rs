@@ -1,18 +1,28 @@
 use std::{collections::HashMap, path::PathBuf};
 use proto::bazel::exec::Digest;
+use common::hash::DigestFunction;
 use common::Result;
 
 /// A service for creating environments for actions to execute in. These are
 /// referred to as "sandboxes" regardless of what security boundary they
 /// enforce.
-pub trait Executor: Sync + Send {
+pub trait Executor: Clone + Sync + Send {
     type Handle: SandboxHandle;
 
     fn spawn(&self, template: &SandboxTemplate) -> Result<Self::Handle>;
+
+    /// Whether sandboxes can contain symlinks with absolute targets. Other
+    /// symlinks are always created as they are, even if they dangle.
+    fn allows_absolute_symlinks(&self) -> bool {
+        false
+    }
 }
 
 /// A reference to a sandbox created by an [`Executor`]. This can then be
 /// populated with files and have an command run within it.
+///
+/// The blobs that [`SandboxHandle::exec`] stores are leased until the handle
+/// is dropped, so that they can't be evicted before the result refers to them.
 pub trait SandboxHandle: Sync + Send {
     fn prepare(&self) -> Result<()>;
 
@@ -28,6 +38,9 @@ pub struct ExecCommand {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SandboxTemplate {
+    /// Function that the digests of inputs are made with, and that outputs
+    /// are stored with.
+    pub digest_function: DigestFunction,
     pub filesystem: Vec<DentryTemplate>,
 }
 
@@ -1,15 +1,17 @@
 use super::{Executor, SandboxHandle};
 use crate::{DentryTemplate, DirTemplate, FileTemplate, SandboxTemplate, SymlinkTemplate};
 use crate::{ExecCommand, ExecResult, GeneratedFile};
+use common::hash::BlobDigest;
 use common::{rand, Error, Result};
 use proto::bazel::exec::Digest;
 use std::fs::{self, OpenOptions};
-use std::io::{BufReader, Cursor, ErrorKind, Write};
+use std::io::{BufReader, Cursor, ErrorKind, Read, Write};
 use std::ops::Drop;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Output};
-use storage::{Store, ProtoStoreExt};
+use std::sync::Mutex;
+use storage::{Lease, ProtoStoreExt, Store};
 
 /// Executes build actions within local directories.
 ///
@@ -21,6 +23,7 @@ pub struct LocalExecutor<S: Store> {
     dir: PathBuf,
     storage: S,
     retain: bool,
+    absolute_symlinks: bool,
 }
 
 impl<S: Store> LocalExecutor<S> {
@@ -30,9 +33,17 @@ impl<S: Store> LocalExecutor<S> {
             dir,
             storage,
             retain,
+            absolute_symlinks: false,
         }
     }
 
+    /// Allow symlinks with absolute targets in sandboxes. They point outside of
+    /// the sandbox, so actions that use them aren't hermetic.
+    pub fn with_absolute_symlinks(mut self, allowed: bool) -> Self {
+        self.absolute_symlinks = allowed;
+        self
+    }
+
     fn generate_id(&self) -> String {
         format!("sandbox-{}", rand::string(10))
     }
@@ -61,8 +72,14 @@ impl<S: Store> Executor for LocalExecutor<S> {
             storage: self.storage.clone(),
             template: template.clone(),
             retain: self.retain,
+            leases: Mutex::new(vec![]),
+            absolute_symlinks: self.absolute_symlinks,
         })
     }
+
+    fn allows_absolute_symlinks(&self) -> bool {
+        self.absolute_symlinks
+    }
 }
 
 /// A handle to the constructed action execution environment.
@@ -72,11 +89,26 @@ pub struct LocalSandbox<S: Store> {
     storage: S,
     template: SandboxTemplate,
     retain: bool,
+    /// Leases on the blobs stored by [`SandboxHandle::exec`].
+    leases: Mutex<Vec<Lease>>,
+    absolute_symlinks: bool,
 }
 
 impl<S: Store> LocalSandbox<S> {
+    /// Store an output of the action, leasing it for as long as the sandbox
+    /// is around.
+    fn store_output(&self, src: impl Read) -> Result<Digest> {
+        let function = self.template.digest_function;
+        let digest = self.storage.write_digest(function, src)?;
+        let name = BlobDigest::from_proto(function, &digest)?.blob_name();
+
+        let lease = self.storage.lease(&[&name]);
+        self.leases.lock().unwrap().push(lease);
+        Ok(digest)
+    }
+
     fn prepare_file(&self, tpl: &FileTemplate) -> Result<()> {
-        let path = self.relative_path(&tpl.path);
+        let path = self.relative_path(&tpl.path)?;
         tracing::info!("Preparing file: {path:?}");
 
         let mut file = OpenOptions::new()
@@ -85,7 +117,9 @@ impl<S: Store> LocalSandbox<S> {
             .open(path)
             .map_err(Error::io)?;
 
-        let mut reader = self.storage.read_digest(&tpl.digest)?;
+        let mut reader = self
+            .storage
+            .read_digest(self.template.digest_function, &tpl.digest)?;
         std::io::copy(&mut reader, &mut file).map_err(Error::io)?;
         file.flush().map_err(Error::io)?;
 
@@ -98,15 +132,25 @@ impl<S: Store> LocalSandbox<S> {
         Ok(())
     }
 
+    /// Create a symlink with exactly the target from the template. Relative
+    /// targets are resolved by the filesystem from the directory of the link,
+    /// and the target doesn't have to exist.
     fn prepare_symlink(&self, symlink: &SymlinkTemplate) -> Result<()> {
         use std::os::unix::fs;
-        let from = self.relative_path(&symlink.path);
-        let to = self.relative_path(&symlink.target);
-        fs::symlink(from, to).map_err(Error::io)
+
+        if symlink.target.is_absolute() && !self.absolute_symlinks {
+            return Err(Error::failed_precondition(&format!(
+                "symlink {:?} has an absolute target",
+                symlink.path
+            )));
+        }
+
+        let path = self.relative_path(&symlink.path)?;
+        fs::symlink(&symlink.target, path).map_err(Error::io)
     }
 
     fn prepare_dir(&self, dir: &DirTemplate) -> Result<()> {
-        let path = self.relative_path(&dir.path);
+        let path = self.relative_path(&dir.path)?;
         if path.exists() {
             return Ok(());
         }
@@ -114,10 +158,21 @@ impl<S: Store> LocalSandbox<S> {
         fs::create_dir(path).map_err(Error::io)
     }
 
-    fn relative_path(&self, path: &PathBuf) -> PathBuf {
-        let mut rel = self.dir.clone();
-        rel.push(path);
-        rel
+    /// Resolve a path from the template within the sandbox. Paths that could
+    /// lead out of the sandbox, like absolute ones or ones with `..` in them,
+    /// are refused.
+    fn relative_path(&self, path: &Path) -> Result<PathBuf> {
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+        if !inside {
+            return Err(Error::failed_precondition(&format!(
+                "path {path:?} is outside of the sandbox"
+            )));
+        }
+
+        Ok(self.dir.join(path))
     }
 }
 
@@ -163,7 +218,7 @@ impl<S: Store> SandboxHandle for LocalSandbox<S> {
         // output files are created before they are written to?
         for rel_path in &exec_cmd.outputs {
             let path = PathBuf::from(rel_path);
-            let sandbox_path = self.relative_path(&path);
+            let sandbox_path = self.relative_path(&path)?;
             tracing::info!("Creating parent directory for: {sandbox_path:?}");
             if let Some(parent) = sandbox_path.parent() {
                 tracing::info!("actually creating parent");
@@ -190,7 +245,7 @@ impl<S: Store> SandboxHandle for LocalSandbox<S> {
 
         let mut outputs = vec![];
         for rel_path in &exec_cmd.outputs {
-            let path = self.relative_path(&PathBuf::from(&rel_path));
+            let path = self.relative_path(&PathBuf::from(&rel_path))?;
             if !path.exists() {
                 continue;
             }
@@ -202,20 +257,20 @@ impl<S: Store> SandboxHandle for LocalSandbox<S> {
 
             outputs.push(GeneratedFile {
                 path: PathBuf::from(&rel_path),
-                digest: self.storage.write_digest(file)?,
+                digest: self.store_output(file)?,
             });
         }
 
         let stdout = {
             let cursor = Cursor::new(&output.stdout);
             let mut reader = BufReader::new(cursor);
-            self.storage.write_digest(reader)
+            self.store_output(reader)
         }?;
 
         let stderr = {
             let cursor = Cursor::new(&output.stderr);
             let mut reader = BufReader::new(cursor);
-            self.storage.write_digest(reader)
+            self.store_output(reader)
         }?;
 
         Ok(ExecResult {
@@ -238,3 +293,164 @@ impl<S :Store> Drop for LocalSandbox<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::hash::DigestFunction;
+    use std::io::Read;
+    use storage::file::{FileStore, GcPolicy};
+    use storage::mem::MemStore;
+
+    fn template(storage: &MemStore, symlinks: &[(&str, &str)]) -> SandboxTemplate {
+        let function = DigestFunction::Sha256;
+        let digest = storage.write_digest(function, &b"hello"[..]).unwrap();
+
+        let mut filesystem = vec![
+            DentryTemplate::Dir(DirTemplate::new(PathBuf::new())),
+            DentryTemplate::Dir(DirTemplate::new(PathBuf::from("dir"))),
+            DentryTemplate::File(FileTemplate {
+                digest,
+                path: PathBuf::from("dir/file"),
+                executable: false,
+            }),
+        ];
+
+        for (path, target) in symlinks {
+            filesystem.push(DentryTemplate::Symlink(SymlinkTemplate {
+                path: PathBuf::from(path),
+                target: PathBuf::from(target),
+            }));
+        }
+
+        SandboxTemplate {
+            digest_function: function,
+            filesystem,
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        let mut data = String::new();
+        fs::File::open(path)
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_relative_symlinks() {
+        let dir = create_temp_dir();
+        let storage = MemStore::new();
+        let executor = LocalExecutor::new(dir.clone(), storage.clone(), false);
+
+        let template = template(
+            &storage,
+            &[
+                ("dir/link", "file"),
+                ("up", "dir/../dir/file"),
+                ("chain", "dir/link"),
+                ("dirlink", "dir"),
+                ("dangling", "missing"),
+            ],
+        );
+
+        let sandbox = executor.spawn(&template).unwrap();
+        sandbox.prepare().unwrap();
+
+        let path = |path: &str| sandbox.dir.join(path);
+        let link = |name: &str| fs::read_link(path(name)).unwrap();
+        assert_eq!(link("dir/link"), PathBuf::from("file"));
+        assert_eq!(link("chain"), PathBuf::from("dir/link"));
+        assert_eq!(link("dangling"), PathBuf::from("missing"));
+
+        assert_eq!(read(path("dir/link")), "hello");
+        assert_eq!(read(path("up")), "hello");
+        assert_eq!(read(path("chain")), "hello");
+        assert_eq!(read(path("dirlink/file")), "hello");
+        assert!(!path("dangling").exists());
+
+        drop(sandbox);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_absolute_symlinks() {
+        let dir = create_temp_dir();
+        let storage = MemStore::new();
+        let target = dir.join("outside");
+        fs::write(&target, "outside").unwrap();
+
+        let symlinks = [("abs", target.to_str().unwrap())];
+        let template = template(&storage, &symlinks);
+
+        let executor = LocalExecutor::new(dir.clone(), storage.clone(), false);
+        assert!(!executor.allows_absolute_symlinks());
+        let sandbox = executor.spawn(&template).unwrap();
+        let err = sandbox.prepare().unwrap_err();
+        assert!(matches!(err, Error::FailedPrecondition(_)));
+        drop(sandbox);
+
+        let executor = executor.with_absolute_symlinks(true);
+        assert!(executor.allows_absolute_symlinks());
+        let sandbox = executor.spawn(&template).unwrap();
+        sandbox.prepare().unwrap();
+        assert_eq!(fs::read_link(sandbox.dir.join("abs")).unwrap(), target);
+        assert_eq!(read(sandbox.dir.join("abs")), "outside");
+
+        drop(sandbox);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_outputs_leased() {
+        let dir = create_temp_dir();
+        let storage = FileStore::open(dir.join("storage")).unwrap();
+        let executor = LocalExecutor::new(dir.clone(), storage.clone(), false);
+        let template = SandboxTemplate {
+            digest_function: DigestFunction::Sha256,
+            filesystem: vec![DentryTemplate::Dir(DirTemplate::new(PathBuf::new()))],
+        };
+
+        let sandbox = executor.spawn(&template).unwrap();
+        sandbox.prepare().unwrap();
+        let cmd = ExecCommand {
+            args: ["/bin/sh", "-c", "echo output > out; echo stdout"]
+                .map(String::from)
+                .to_vec(),
+            env: Default::default(),
+            outputs: vec!["out".to_string()],
+        };
+        let res = sandbox.exec(&cmd).unwrap();
+        assert_eq!(res.exit_code, 0);
+
+        let policy = GcPolicy {
+            max_size_bytes: Some(0),
+            min_free_bytes: None,
+        };
+        let blobs = [&res.outputs[0].digest, &res.stdout, &res.stderr];
+
+        // Nothing can be evicted until the sandbox is dropped.
+        storage.collect_garbage(&policy).unwrap();
+        for digest in blobs {
+            assert!(storage.contains(&digest.hash).unwrap());
+        }
+
+        // The empty stderr doesn't count towards the size of the store, so
+        // it's left alone.
+        drop(sandbox);
+        storage.collect_garbage(&policy).unwrap();
+        for digest in &blobs[..2] {
+            assert!(!storage.contains(&digest.hash).unwrap());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn create_temp_dir() -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rust-test-{}", rand::string(20)));
+        fs::create_dir(&path).unwrap();
+        path
+    }
+}
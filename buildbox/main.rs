@@ -3,6 +3,7 @@ use common::{config::Config, Error, Result};
 use proto::buildbox::{BuildboxClient, FindSandboxesRequest, FindBlobsRequest};
 use std::process::ExitCode;
 use std::{path::PathBuf, str::FromStr};
+use storage::file::FileStore;
 use tracing_subscriber::{EnvFilter, filter::LevelFilter, layer::SubscriberExt};
 use tracing_subscriber::util::SubscriberInitExt;
 use tonic::{transport::Endpoint, Request};
@@ -27,6 +28,8 @@ pub enum Command {
     /// List the blobs
     #[clap(name = "blobs")]
     ListBlobs(ListBlobsCmd),
+    /// Check the stored blobs, and quarantine the ones that are corrupt
+    Scrub(ScrubCmd),
 }
 
 #[derive(Args, Debug)]
@@ -48,6 +51,13 @@ pub struct ListBlobsCmd {
     pub addr: Option<String>,
 }
 
+#[derive(Args, Debug)]
+pub struct ScrubCmd {
+    /// Path to the configuration file.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
     init_tracing_or_die();
@@ -56,6 +66,7 @@ async fn main() -> ExitCode {
         Command::Up(cmd) => up(&cmd).await,
         Command::ListSandboxes(cmd) => list_sandboxes(&cmd).await,
         Command::ListBlobs(cmd) => list_blobs(&cmd).await,
+        Command::Scrub(cmd) => scrub(&cmd),
     };
 
     if let Err(err) = res {
@@ -118,11 +129,42 @@ async fn list_blobs(args: &ListBlobsCmd) -> Result<()> {
     })?;
 
     let req = Request::new(FindBlobsRequest {});
-    let res = client.find_blobs(req).await.map_err(Error::boxed)?;
+    let mut stream = client.find_blobs(req).await.map_err(Error::boxed)?.into_inner();
 
     println!("NAME");
-    for blob in &res.into_inner().blobs {
-        println!("{blob}");
+    while let Some(res) = stream.message().await.map_err(Error::boxed)? {
+        for blob in &res.blobs {
+            println!("{blob}");
+        }
+    }
+
+    Ok(())
+}
+
+fn scrub(cmd: &ScrubCmd) -> Result<()> {
+    let config = Config::load(cmd.config.as_ref())?;
+
+    // Instances with storage of their own keep their blobs apart from the
+    // rest, so they're checked separately.
+    let mut dirs = vec![PathBuf::from(&config.storage_dir)];
+    dirs.extend(
+        config
+            .instances
+            .keys()
+            .filter_map(|name| config.instance_storage_dir(name)),
+    );
+
+    for dir in dirs {
+        let store = FileStore::open(dir.clone())?;
+        let report = store.scrub()?;
+
+        println!("Checked {} blobs in {}", report.checked_blobs, dir.display());
+        for path in &report.corrupt {
+            println!("corrupt: {}", path.display());
+        }
+        for path in &report.misnamed {
+            println!("misnamed: {}", path.display());
+        }
     }
 
     Ok(())
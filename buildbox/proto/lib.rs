@@ -23,7 +23,11 @@ pub mod bazel {
 pub mod google {
     pub use any_proto::google::protobuf;
     pub use operations_proto::google::longrunning;
-    pub use status_proto::google::rpc;
+
+    pub mod rpc {
+        pub use error_details_proto::google::rpc::*;
+        pub use status_proto::google::rpc::*;
+    }
 
     pub mod bytestream {
         pub use bytestream_proto::google::bytestream::*;
@@ -1,41 +1,306 @@
+use super::instance::Instances;
+use super::{blocking, into_status, required_digest};
+use common::hash::{BlobDigest, DigestFunction};
 use common::Error;
 pub use proto::bazel::exec::{
-    Action, ActionCache, ActionResult, Command, Digest, GetActionResultRequest, OutputFile,
-    UpdateActionResultRequest,
+    Action, ActionCache, ActionResult, Command, Digest, Directory, GetActionResultRequest,
+    OutputFile, Tree, UpdateActionResultRequest,
 };
-use storage::Store;
+use std::io::Read;
+use storage::{ProtoStoreExt, Store};
 use tonic::{Request, Response, Status};
 
-#[derive(Debug)]
-pub struct ActionCacheService<S>
+/// Largest blob that will be inlined into an [`ActionResult`] when the client
+/// asks for it. Bigger blobs are left for the client to fetch from the CAS.
+const MAX_INLINE_SIZE_BYTES: i64 = 1024 * 1024;
+
+/// The action cache maps action digests onto the [`ActionResult`] produced by
+/// executing them. Results are stored in their own [`Store`] so that they
+/// cannot be confused with content-addressed blobs, which they reference.
+///
+/// The blobs a result references are made with the same digest function as
+/// the action, and results are keyed by the storage name of the action digest
+/// so that actions hashed with different functions never share a result.
+///
+/// Every instance has an action cache of its own, so that results are never
+/// shared between instances.
+#[derive(Debug, Clone)]
+pub struct ActionCacheService<S, A = S>
+where
+    S: Store + 'static,
+    A: Store + 'static,
+{
+    instances: Instances<ActionCacheInstance<S, A>>,
+}
+
+impl<S, A> ActionCacheService<S, A>
 where
     S: Store + 'static,
+    A: Store + 'static,
 {
+    /// Create a new [`ActionCacheService`]. The `store` is the CAS that
+    /// outputs are read from, while `action_cache` holds the results of the
+    /// default instance.
+    pub fn new(store: S, action_cache: A) -> Self {
+        Self {
+            instances: Instances::new(ActionCacheInstance::new(store, action_cache)),
+        }
+    }
+
+    /// Serve the instance called `name`, with outputs read from `store` and
+    /// results kept in `action_cache`. Clients can't update the results of a
+    /// `read_only` instance.
+    pub fn with_instance(mut self, name: &str, store: S, action_cache: A, read_only: bool) -> Self {
+        let instance = ActionCacheInstance {
+            read_only,
+            ..ActionCacheInstance::new(store, action_cache)
+        };
+
+        self.instances.insert(name, instance);
+        self
+    }
+
+    pub(crate) fn instance(&self, name: &str) -> Result<ActionCacheInstance<S, A>, Error> {
+        self.instances.get(name).cloned()
+    }
+}
+
+/// The action cache of a single instance.
+#[derive(Debug, Clone)]
+pub(crate) struct ActionCacheInstance<S, A> {
     store: S,
+    action_cache: A,
+    read_only: bool,
 }
 
-impl<S> ActionCacheService<S>
+impl<S, A> ActionCacheInstance<S, A>
 where
     S: Store + 'static,
+    A: Store + 'static,
 {
-    pub fn new(store: S) -> Self {
-        Self { store }
+    pub(crate) fn new(store: S, action_cache: A) -> Self {
+        Self {
+            store,
+            action_cache,
+            read_only: false,
+        }
+    }
+
+    /// Find the cached result of an action, if there is one. Results that
+    /// refer to blobs no longer in the CAS are treated as missing, so that
+    /// clients never get a hit they can't download the outputs of.
+    pub(crate) fn lookup(&self, action_digest: &BlobDigest) -> Result<Option<ActionResult>, Error> {
+        let function = action_digest.function();
+        if !self.action_cache.contains(&action_digest.blob_name())? {
+            return Ok(None);
+        }
+
+        let result = self
+            .action_cache
+            .read_message::<ActionResult>(function, &action_digest.clone().into())?;
+
+        // Blobs that have gone missing don't come back by themselves, so an
+        // incomplete result is removed rather than checked on every lookup.
+        if !self.is_complete(function, &result)? {
+            tracing::warn!("Removing incomplete action result {}", action_digest.hash());
+            if let Err(err) = self.action_cache.delete(&action_digest.blob_name()) {
+                tracing::warn!("Failed to remove action result: {err}");
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Store the result of an action, replacing any previous result.
+    pub(crate) fn update(
+        &self,
+        action_digest: &BlobDigest,
+        result: &ActionResult,
+    ) -> Result<(), Error> {
+        self.action_cache
+            .write_message(&action_digest.blob_name(), result)
+    }
+
+    /// Check that every blob referenced by the result is in the CAS.
+    fn is_complete(&self, function: DigestFunction, result: &ActionResult) -> Result<bool, Error> {
+        let mut digests = vec![];
+        digests.extend(result.stdout_digest.iter());
+        digests.extend(result.stderr_digest.iter());
+        digests.extend(
+            result
+                .output_files
+                .iter()
+                .filter_map(|file| file.digest.as_ref()),
+        );
+
+        for digest in digests {
+            if !self.contains(function, digest)? {
+                return Ok(false);
+            }
+        }
+
+        for output in &result.output_directories {
+            let Some(tree_digest) = &output.tree_digest else {
+                return Ok(false);
+            };
+
+            if !self.contains(function, tree_digest)? {
+                return Ok(false);
+            }
+
+            let tree = self.store.read_message::<Tree>(function, tree_digest)?;
+            for dir in tree.root.iter().chain(tree.children.iter()) {
+                if !self.is_directory_complete(function, dir)? {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn is_directory_complete(
+        &self,
+        function: DigestFunction,
+        dir: &Directory,
+    ) -> Result<bool, Error> {
+        for file in &dir.files {
+            let Some(digest) = &file.digest else {
+                return Ok(false);
+            };
+
+            if !self.contains(function, digest)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Whether the CAS holds a blob. Clients never upload the empty blob, so
+    /// it's always considered present.
+    fn contains(&self, function: DigestFunction, digest: &Digest) -> Result<bool, Error> {
+        let digest = BlobDigest::from_proto(function, digest)?;
+        if digest.size_bytes() == 0 {
+            return Ok(true);
+        }
+
+        self.store.contains(&digest.blob_name())
+    }
+
+    fn get_action_result(&self, req: &GetActionResultRequest) -> Result<ActionResult, Error> {
+        let action_digest = required_digest(
+            req.digest_function,
+            req.action_digest.as_ref(),
+            "action digest",
+        )?;
+        let function = action_digest.function();
+
+        let mut result = self
+            .lookup(&action_digest)?
+            .ok_or_else(|| Error::not_found("action not found"))?;
+
+        if req.inline_stdout {
+            if let Some(digest) = &result.stdout_digest {
+                result.stdout_raw = self.read_inline(function, digest)?;
+            }
+        }
+
+        if req.inline_stderr {
+            if let Some(digest) = &result.stderr_digest {
+                result.stderr_raw = self.read_inline(function, digest)?;
+            }
+        }
+
+        for output in &mut result.output_files {
+            if !req.inline_output_files.contains(&output.path) {
+                continue;
+            }
+
+            if let Some(digest) = &output.digest {
+                output.contents = self.read_inline(function, digest)?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn update_action_result(&self, req: &UpdateActionResultRequest) -> Result<ActionResult, Error> {
+        if self.read_only {
+            return Err(Error::permission_denied(
+                "the action cache of this instance is read-only",
+            ));
+        }
+
+        let action_digest = required_digest(
+            req.digest_function,
+            req.action_digest.as_ref(),
+            "action digest",
+        )?;
+        let function = action_digest.function();
+
+        let result = req
+            .action_result
+            .as_ref()
+            .ok_or_else(|| Error::invalid("missing action result"))?;
+
+        // The digests in the result are looked up in the CAS whenever the
+        // result is read back.
+        let outputs = result
+            .output_files
+            .iter()
+            .filter_map(|file| file.digest.as_ref())
+            .chain(
+                result
+                    .output_directories
+                    .iter()
+                    .filter_map(|dir| dir.tree_digest.as_ref()),
+            );
+
+        for digest in result
+            .stdout_digest
+            .iter()
+            .chain(&result.stderr_digest)
+            .chain(outputs)
+        {
+            BlobDigest::from_proto(function, digest)?;
+        }
+
+        self.update(&action_digest, result)?;
+        Ok(result.clone())
+    }
+
+    /// Read a blob to be inlined into a response. Blobs that are too large are
+    /// skipped, which the protocol allows.
+    fn read_inline(&self, function: DigestFunction, digest: &Digest) -> Result<Vec<u8>, Error> {
+        if digest.size_bytes > MAX_INLINE_SIZE_BYTES {
+            return Ok(vec![]);
+        }
+
+        let mut buf = vec![];
+        let mut reader = self.store.read_digest(function, digest)?;
+        reader.read_to_end(&mut buf).map_err(Error::io)?;
+        Ok(buf)
     }
 }
 
 #[async_trait::async_trait]
-impl<S> ActionCache for ActionCacheService<S>
+impl<S, A> ActionCache for ActionCacheService<S, A>
 where
     S: Store + 'static,
+    A: Store + 'static,
 {
     async fn get_action_result(
         &self,
         req: Request<GetActionResultRequest>,
     ) -> Result<Response<ActionResult>, Status> {
         let req = req.into_inner();
-        let hash = req.action_digest.clone().unwrap().hash;
-        tracing::info!("ActionCache::get_action_result {req:?}");
-        Err(Status::not_found("action not found"))
+        tracing::info!("ActionCache::get_action_result {:?}", req.action_digest);
+
+        let instance = self.instance(&req.instance_name).map_err(into_status)?;
+        let result = blocking(move || instance.get_action_result(&req)).await?;
+        Ok(Response::new(result))
     }
 
     async fn update_action_result(
@@ -43,8 +308,194 @@ where
         req: Request<UpdateActionResultRequest>,
     ) -> Result<Response<ActionResult>, Status> {
         let req = req.into_inner();
-        let hash = req.action_digest.unwrap().hash;
-        tracing::info!("ActionCache::update_action_result {hash}");
-        Ok(Response::new(ActionResult::default()))
+        tracing::info!("ActionCache::update_action_result {:?}", req.action_digest);
+
+        let instance = self.instance(&req.instance_name).map_err(into_status)?;
+        let result = blocking(move || instance.update_action_result(&req)).await?;
+        Ok(Response::new(result))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::hash;
+    use prost::Message;
+    use proto::bazel::exec::{FileNode, OutputDirectory};
+    use storage::mem::MemStore;
+    use tonic::Code;
+
+    const FUNCTION: DigestFunction = DigestFunction::Sha256;
+
+    fn blob_name(digest: &Digest) -> String {
+        BlobDigest::from_proto(FUNCTION, digest)
+            .unwrap()
+            .blob_name()
+    }
+
+    /// Store a result whose stdout, output file, tree and file in the tree
+    /// are all in the CAS, and return the digests of those blobs.
+    fn store_result(store: &MemStore, action_cache: &MemStore) -> (Digest, Vec<Digest>) {
+        let write = |data: &[u8]| store.write_digest(FUNCTION, data).unwrap();
+        let stdout = write(b"stdout");
+        let output = write(b"output");
+        let file = write(b"file");
+
+        let tree = Tree {
+            root: Some(Directory {
+                files: vec![FileNode {
+                    name: "file".to_string(),
+                    digest: Some(file.clone()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            children: vec![],
+        };
+        let tree_digest = write(&tree.encode_to_vec());
+
+        let result = ActionResult {
+            stdout_digest: Some(stdout.clone()),
+            output_files: vec![OutputFile {
+                path: "output".to_string(),
+                digest: Some(output.clone()),
+                ..Default::default()
+            }],
+            output_directories: vec![OutputDirectory {
+                path: "dir".to_string(),
+                tree_digest: Some(tree_digest.clone()),
+            }],
+            ..Default::default()
+        };
+
+        let action_digest = Digest {
+            hash: hash::sha256(b"action").to_string(),
+            size_bytes: 6,
+        };
+        action_cache
+            .write_message(&blob_name(&action_digest), &result)
+            .unwrap();
+
+        (action_digest, vec![stdout, output, tree_digest, file])
+    }
+
+    async fn get(
+        service: &ActionCacheService<MemStore>,
+        action_digest: &Digest,
+    ) -> Result<ActionResult, Status> {
+        let req = GetActionResultRequest {
+            action_digest: Some(action_digest.clone()),
+            ..Default::default()
+        };
+
+        ActionCache::get_action_result(service, Request::new(req))
+            .await
+            .map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn test_update_action_result() {
+        let (store, action_cache) = (MemStore::new(), MemStore::new());
+        let service = ActionCacheService::new(store.clone(), action_cache);
+        let action_digest = Digest {
+            hash: hash::sha256(b"action").to_string(),
+            size_bytes: 6,
+        };
+
+        let err = get(&service, &action_digest).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let result = ActionResult {
+            exit_code: 1,
+            stdout_digest: Some(store.write_digest(FUNCTION, &b"stdout"[..]).unwrap()),
+            ..Default::default()
+        };
+        let req = UpdateActionResultRequest {
+            action_digest: Some(action_digest.clone()),
+            action_result: Some(result.clone()),
+            ..Default::default()
+        };
+        let updated = ActionCache::update_action_result(&service, Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(updated, result);
+        assert_eq!(get(&service, &action_digest).await.unwrap(), result);
+
+        let req = GetActionResultRequest {
+            action_digest: Some(action_digest.clone()),
+            inline_stdout: true,
+            ..Default::default()
+        };
+        let inlined = ActionCache::get_action_result(&service, Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(inlined.stdout_raw, b"stdout");
+
+        let req = UpdateActionResultRequest {
+            action_digest: Some(action_digest),
+            ..Default::default()
+        };
+        let err = ActionCache::update_action_result(&service, Request::new(req))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_complete_result() {
+        let (store, action_cache) = (MemStore::new(), MemStore::new());
+        let service = ActionCacheService::new(store.clone(), action_cache.clone());
+        let (action_digest, _) = store_result(&store, &action_cache);
+
+        let result = get(&service, &action_digest).await.unwrap();
+        assert_eq!(result.output_files[0].path, "output");
+        assert!(action_cache.contains(&blob_name(&action_digest)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_result() {
+        let (store, action_cache) = (MemStore::new(), MemStore::new());
+        let service = ActionCacheService::new(store.clone(), action_cache.clone());
+        let (action_digest, blobs) = store_result(&store, &action_cache);
+
+        for missing in &blobs {
+            store_result(&store, &action_cache);
+            store.delete(&blob_name(missing)).unwrap();
+
+            let err = get(&service, &action_digest).await.unwrap_err();
+            assert_eq!(err.code(), Code::NotFound, "missing {missing:?}");
+            assert!(!action_cache.contains(&blob_name(&action_digest)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_action_digest() {
+        let (store, action_cache) = (MemStore::new(), MemStore::new());
+        let service = ActionCacheService::new(store, action_cache.clone());
+        let hash = hash::sha256(b"action").to_string();
+        let malformed =
+            [("../../etc/passwd", 6), (&hash[..], -1)].map(|(hash, size_bytes)| Digest {
+                hash: hash.to_string(),
+                size_bytes,
+            });
+
+        for digest in malformed {
+            let err = get(&service, &digest).await.unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "{digest:?}");
+
+            let req = UpdateActionResultRequest {
+                action_digest: Some(digest.clone()),
+                action_result: Some(ActionResult::default()),
+                ..Default::default()
+            };
+            let err = ActionCache::update_action_result(&service, Request::new(req))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "{digest:?}");
+        }
+
+        assert!(action_cache.list().unwrap().next().is_none());
     }
 }
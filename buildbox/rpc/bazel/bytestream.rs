@@ -5,36 +5,310 @@
 //! services assume that a `bytestream` service is available. The bytestream
 //! service is used by the remote execution and caching services to store
 //! inputs, action digests, output artifacts, etc.
+//!
+//! Blobs can also be transferred compressed by using `compressed-blobs`
+//! instead of `blobs` in the resource name. They are compressed and
+//! decompressed on the fly, and always stored uncompressed.
+//!
+//! The digest function of a blob can be named before its hash, as in
+//! `blobs/blake3/{hash}/{size}`. It's inferred from the length of the hash
+//! otherwise.
 
-use super::ResponseStream;
+use super::compression::{self, Compressor};
+use super::instance::Instances;
+use super::{blocking, into_status};
+use common::hash::{BlobDigest, DigestFunction, Hasher};
 use common::Error;
 use proto::google::bytestream::{
     ByteStream, QueryWriteStatusRequest, QueryWriteStatusResponse, ReadRequest, ReadResponse,
     WriteRequest, WriteResponse,
 };
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{
-    io::{Cursor, Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     str::FromStr,
 };
-use storage::{Store, WriteHandle};
+use storage::{ReadHandle, Store, WriteHandle};
 use tokio::sync::mpsc;
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 
+type Uploads<W> = Arc<Mutex<HashMap<String, PendingUpload<W>>>>;
+
 /// Effectively the read/write input to the CAS for large byte payloads.
+///
+/// Uploads that are interrupted before they finish are kept, keyed by their
+/// resource name, so that the client can resume them from the offset reported
+/// by `QueryWriteStatus`. Uploads that stay idle for longer than the upload
+/// timeout are discarded.
+///
+/// The instance name at the start of a resource name picks the store that
+/// the blob is read from or written to.
 #[derive(Debug)]
-pub struct ByteStreamService<S> {
-    store: S,
+pub struct ByteStreamService<S>
+where
+    S: Store,
+{
+    stores: Instances<S>,
+    uploads: Uploads<S::WriteHandle>,
+    upload_timeout: Duration,
+    read_chunk_size: usize,
+}
+
+// Deriving `Clone` would needlessly require the write handles to be `Clone`.
+impl<S: Store> Clone for ByteStreamService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            stores: self.stores.clone(),
+            uploads: self.uploads.clone(),
+            upload_timeout: self.upload_timeout,
+            read_chunk_size: self.read_chunk_size,
+        }
+    }
 }
 
 impl<S> ByteStreamService<S>
 where
     S: Store + 'static,
 {
-    /// Create a new [`ByteStreamService`] instance.
+    /// Create a new [`ByteStreamService`] instance, whose default instance
+    /// keeps blobs in `store`.
     #[must_use]
-    pub fn new(store: S) -> Self {
-        Self { store }
+    pub fn new(store: S, upload_timeout: Duration, read_chunk_size: usize) -> Self {
+        Self {
+            stores: Instances::new(store),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            upload_timeout,
+            read_chunk_size,
+        }
+    }
+
+    /// Serve the instance called `name` from `store`.
+    #[must_use]
+    pub fn with_instance(mut self, name: &str, store: S) -> Self {
+        self.stores.insert(name, store);
+        self
+    }
+
+    fn store(&self, instance_name: &str) -> Result<S, Error> {
+        self.stores.get(instance_name).cloned()
+    }
+
+    /// Find the upload to continue writing to at `offset`, or `None` if the
+    /// blob has been uploaded by somebody else in the meantime, in which case
+    /// the write can finish early.
+    fn prepare_upload(
+        &self,
+        store: &S,
+        resource_name: &str,
+        name: &ResourceName,
+        offset: i64,
+    ) -> Result<Option<PendingUpload<S::WriteHandle>>, Error> {
+        self.expire_idle_uploads();
+
+        if store.contains(&name.digest.blob_name())? {
+            self.discard_upload(resource_name);
+            return Ok(None);
+        }
+
+        self.resume_upload(store, resource_name, name, offset)
+            .map(Some)
+    }
+
+    /// Find the upload to continue writing to at `offset`, or start a new one.
+    fn resume_upload(
+        &self,
+        store: &S,
+        resource_name: &str,
+        name: &ResourceName,
+        offset: i64,
+    ) -> Result<PendingUpload<S::WriteHandle>, Error> {
+        let pending = self.uploads.lock().unwrap().remove(resource_name);
+
+        match pending {
+            Some(upload) if upload.committed as i64 == offset => Ok(upload),
+            Some(upload) if offset == 0 => {
+                // The client has decided to start again from scratch.
+                upload.abort();
+                self.start_upload(store, name)
+            }
+            Some(upload) => {
+                let committed = upload.committed;
+                self.uploads
+                    .lock()
+                    .unwrap()
+                    .insert(resource_name.to_string(), upload);
+                Err(Error::invalid(&format!(
+                    "write offset {offset} does not match committed size {committed}"
+                )))
+            }
+            None if offset == 0 => self.start_upload(store, name),
+            None => Err(Error::invalid(&format!(
+                "cannot resume unknown upload at offset {offset}"
+            ))),
+        }
+    }
+
+    /// Open a blob to be read, checking that it has the size the client
+    /// expects.
+    fn open_blob(&self, store: &S, name: &BlobResourceName) -> Result<S::ReadHandle, Error> {
+        let reader = store.read(&name.digest.blob_name())?;
+
+        let size = reader.metadata()?.size;
+        if size != name.digest.size_bytes() {
+            return Err(Error::invalid(&format!(
+                "expected {} bytes but blob has {size}",
+                name.digest.size_bytes()
+            )));
+        }
+
+        Ok(reader)
+    }
+
+    /// Open a blob to be streamed to the client from `offset`, compressing it
+    /// on the fly if needed. Offsets are into the compressed data, whose size
+    /// isn't known until it has been compressed, so they're checked as it's
+    /// skipped over. Returns `None` if the offset is past the end of it.
+    fn open_reader(
+        &self,
+        store: &S,
+        name: &BlobResourceName,
+        offset: u64,
+    ) -> Result<Option<Box<dyn Read + Send>>, Error> {
+        match name.compressor {
+            // The empty blob is never uploaded, so there's nothing to read.
+            Compressor::Identity if name.digest.size_bytes() == 0 => {
+                Ok(Some(Box::new(io::empty())))
+            }
+            Compressor::Identity => {
+                // Seeking makes a verifying store check the whole blob up
+                // front, so it's only done when the read doesn't start at
+                // the beginning.
+                let mut reader = self.open_blob(store, name)?;
+                if offset > 0 {
+                    reader.seek(SeekFrom::Start(offset)).map_err(Error::io)?;
+                }
+                Ok(Some(Box::new(reader)))
+            }
+            compressor => {
+                // Even the empty blob needs a valid compressed frame.
+                let blob: Box<dyn Read + Send> = if name.digest.size_bytes() == 0 {
+                    Box::new(io::empty())
+                } else {
+                    Box::new(self.open_blob(store, name)?)
+                };
+
+                let mut reader = compress_reader(compressor, blob)?;
+                let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink())
+                    .map_err(Error::io)?;
+                if skipped < offset {
+                    return Ok(None);
+                }
+
+                Ok(Some(reader))
+            }
+        }
+    }
+
+    /// Store the blob uploaded by a stream of write requests, or keep it to
+    /// be resumed if the stream ends before the client finishes the write.
+    async fn receive_write<R>(&self, mut stream: R) -> Result<WriteResponse, Status>
+    where
+        R: Stream<Item = Result<WriteRequest, Status>> + Unpin + Send,
+    {
+        let first = stream
+            .next()
+            .await
+            .ok_or_else(|| Status::invalid_argument("did not receive resource name"))??;
+
+        let resource_name = first.resource_name.clone();
+        let name = ResourceName::parse(&resource_name)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        tracing::info!(
+            "ByteStream::write hash={:?} offset={}",
+            name.digest.hash(),
+            first.write_offset
+        );
+
+        let service = self.clone();
+        let store = self.store(&name.instance_name).map_err(into_status)?;
+        let (upload_resource_name, upload_name) = (resource_name.clone(), name.clone());
+        let offset = first.write_offset;
+        let upload = blocking(move || {
+            service.prepare_upload(&store, &upload_resource_name, &upload_name, offset)
+        })
+        .await?;
+
+        let Some(upload) = upload else {
+            return Ok(WriteResponse {
+                committed_size: name.complete_size(),
+            });
+        };
+
+        match receive_upload(&mut stream, first, &name, upload).await {
+            Ok((upload, true)) => {
+                tracing::info!("Writing {}", name.digest.hash());
+                let committed_size = upload.committed as i64;
+                blocking(move || {
+                    upload.seal(&name).inspect_err(|err| {
+                        tracing::error!("Failed to seal upload: {err}");
+                    })
+                })
+                .await?;
+
+                Ok(WriteResponse { committed_size })
+            }
+            Ok((upload, false)) => {
+                let committed_size = upload.committed as i64;
+                self.suspend_upload(resource_name, upload);
+                Ok(WriteResponse { committed_size })
+            }
+            Err(err) => {
+                tracing::error!("Failed to receive upload: {err}");
+                Err(into_status(err))
+            }
+        }
+    }
+
+    fn start_upload(
+        &self,
+        store: &S,
+        name: &ResourceName,
+    ) -> Result<PendingUpload<S::WriteHandle>, Error> {
+        let writer = store.write()?;
+        PendingUpload::new(writer, name.compressor, name.digest.function())
+    }
+
+    /// Keep an unfinished upload around so that it can be resumed later.
+    fn suspend_upload(&self, resource_name: String, upload: PendingUpload<S::WriteHandle>) {
+        self.uploads.lock().unwrap().insert(resource_name, upload);
+    }
+
+    fn discard_upload(&self, resource_name: &str) {
+        if let Some(upload) = self.uploads.lock().unwrap().remove(resource_name) {
+            upload.abort();
+        }
+    }
+
+    /// Discard uploads that the client seems to have given up on.
+    fn expire_idle_uploads(&self) {
+        let mut uploads = self.uploads.lock().unwrap();
+
+        let expired = uploads
+            .iter()
+            .filter(|(_, upload)| upload.last_active.elapsed() > self.upload_timeout)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        for name in expired {
+            tracing::info!("Discarding idle upload {name}");
+            if let Some(upload) = uploads.remove(&name) {
+                upload.abort();
+            }
+        }
     }
 }
 
@@ -49,36 +323,59 @@ where
         let req = req.into_inner();
         tracing::info!("ByteStream::read {req:?}");
 
-        let parts = req.resource_name.split("/").collect::<Vec<&str>>();
-        let hash = &parts[1];
+        let name = BlobResourceName::parse(&req.resource_name)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
 
-        let mut reader = self
-            .store
-            .read(hash)
-            .map_err(|err| Status::internal(err.to_string()))?;
+        if req.read_offset < 0 {
+            return Err(out_of_range(req.read_offset, name.digest.size_bytes()));
+        }
 
-        let (tx, rx) = mpsc::channel(1024);
+        let offset = req.read_offset as u64;
+        if name.compressor == Compressor::Identity && offset > name.digest.size_bytes() {
+            return Err(out_of_range(offset, name.digest.size_bytes()));
+        }
+
+        if req.read_limit < 0 {
+            return Err(Status::invalid_argument("read limit must not be negative"));
+        }
+
+        let service = self.clone();
+        let store = self.store(&name.instance_name).map_err(into_status)?;
+        let blob_name = name.clone();
+        let reader = blocking(move || service.open_reader(&store, &blob_name, offset))
+            .await?
+            .ok_or_else(|| out_of_range(offset, name.digest.size_bytes()))?;
 
-        tokio::spawn(async move {
+        let limit = match req.read_limit {
+            0 => u64::MAX,
+            limit => limit as u64,
+        };
+
+        let mut reader = reader.take(limit);
+        let chunk_size = self.read_chunk_size;
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::task::spawn_blocking(move || {
             loop {
-                let mut data = vec![0; 1024];
-                let read = reader
-                    .read(&mut data)
-                    .map_err(|err| Status::internal("failed to read"))
-                    .unwrap();
+                let mut data = vec![0; chunk_size];
+                let read = match reader.read(&mut data) {
+                    Ok(read) => read,
+                    Err(err) => {
+                        tracing::error!("Failed to read {}: {err}", name.digest.hash());
+                        let _ = tx.blocking_send(Err(Status::internal(err.to_string())));
+                        return;
+                    }
+                };
 
                 if read == 0 {
                     break;
                 }
 
-                let res = ReadResponse {
-                    data: data[0..read].to_vec(),
-                };
-
-                tx.send(Ok(res))
-                    .await
-                    .map_err(|err| Status::internal("failed to send execute response"))
-                    .unwrap();
+                data.truncate(read);
+                if tx.blocking_send(Ok(ReadResponse { data })).is_err() {
+                    // The client has gone away.
+                    break;
+                }
             }
         });
 
@@ -89,106 +386,698 @@ where
         &self,
         req: Request<Streaming<WriteRequest>>,
     ) -> Result<Response<WriteResponse>, Status> {
-        let mut stream = req.into_inner();
-
-        let mut name = None;
-        let mut data: Vec<u8> = vec![];
-        let mut received = 0;
-
-        while let Some(Ok(req)) = stream.next().await {
-            if &req.resource_name != "" {
-                let resource_name = ResourceName::parse(&req.resource_name)
-                    .map_err(|err| Status::invalid_argument(err.to_string()))?;
-                tracing::info!("ByteStream::write hash={:?}", resource_name.hash);
-                name = Some(resource_name.clone());
-
-                if resource_name.uuid
-                    == "hash=d0b06d284064528c34e0177821b739e116582907f4de5e0886cde3476483367a"
-                {
-                    tracing::warn!("GOT {req:?}");
-                }
+        self.receive_write(req.into_inner())
+            .await
+            .map(Response::new)
+    }
+
+    async fn query_write_status(
+        &self,
+        req: Request<QueryWriteStatusRequest>,
+    ) -> Result<Response<QueryWriteStatusResponse>, Status> {
+        let req = req.into_inner();
+        tracing::info!("ByteStream::query_write_status {req:?}");
+
+        let name = ResourceName::parse(&req.resource_name)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let service = self.clone();
+        let store = self.store(&name.instance_name).map_err(into_status)?;
+        let blob_name = name.digest.blob_name();
+        let complete = blocking(move || {
+            service.expire_idle_uploads();
+            store.contains(&blob_name)
+        })
+        .await?;
+
+        if complete {
+            return Ok(Response::new(QueryWriteStatusResponse {
+                committed_size: name.complete_size(),
+                complete: true,
+            }));
+        }
+
+        let uploads = self.uploads.lock().unwrap();
+        let upload = uploads
+            .get(&req.resource_name)
+            .ok_or_else(|| Status::not_found("upload not found"))?;
+
+        Ok(Response::new(QueryWriteStatusResponse {
+            committed_size: upload.committed as i64,
+            complete: false,
+        }))
+    }
+}
+
+/// An upload that has been started but not yet sealed into the store.
+struct PendingUpload<W: Write> {
+    sink: UploadSink<W>,
+    /// Number of bytes received from the client, which are compressed if the
+    /// upload is.
+    committed: u64,
+    last_active: Instant,
+}
+
+/// Where the data received for an upload goes, which depends on how it's
+/// compressed.
+enum UploadSink<W: Write> {
+    Identity(BlobWriter<W>),
+    Zstd(zstd::stream::write::Decoder<'static, BlobWriter<W>>),
+}
+
+impl<W: WriteHandle> PendingUpload<W> {
+    fn new(writer: W, compressor: Compressor, function: DigestFunction) -> Result<Self, Error> {
+        let writer = BlobWriter::new(writer, function);
+
+        let sink = match compressor {
+            Compressor::Identity => UploadSink::Identity(writer),
+            Compressor::Zstd => {
+                UploadSink::Zstd(zstd::stream::write::Decoder::new(writer).map_err(Error::io)?)
             }
+            _ => return Err(Error::invalid("unsupported compressor")),
+        };
+
+        Ok(Self {
+            sink,
+            committed: 0,
+            last_active: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.sink {
+            UploadSink::Identity(writer) => writer.write_all(data)?,
+            UploadSink::Zstd(decoder) => decoder.write_all(data)?,
+        }
+
+        self.committed += data.len() as u64;
+        self.last_active = Instant::now();
+        Ok(())
+    }
 
-            data.extend(req.data.iter());
+    /// Number of uncompressed bytes written so far.
+    fn size(&self) -> u64 {
+        match &self.sink {
+            UploadSink::Identity(writer) => writer.size,
+            UploadSink::Zstd(decoder) => decoder.get_ref().size,
+        }
+    }
 
-            if req.finish_write {
-                break;
+    /// Decompress anything still buffered and hand back the underlying writer,
+    /// along with any error from doing so.
+    fn finish(self) -> (BlobWriter<W>, io::Result<()>) {
+        match self.sink {
+            UploadSink::Identity(writer) => (writer, Ok(())),
+            UploadSink::Zstd(mut decoder) => {
+                let flushed = decoder.flush();
+                (decoder.into_inner(), flushed)
             }
         }
+    }
 
-        let name = name.ok_or_else(|| Status::invalid_argument("did not receive resource name"))?;
+    /// Check that the data matches the digest in the resource name, and store
+    /// it under that digest if so. Data that doesn't match is discarded.
+    fn seal(self, name: &ResourceName) -> Result<(), Error> {
+        let (mut writer, flushed) = self.finish();
+        if let Err(err) = flushed {
+            writer.abort();
+            return Err(Error::invalid(&format!("invalid compressed data: {err}")));
+        }
 
-        if data.len() != name.size as usize {
-            let err = format!("Expected {} bytes but received {}", name.size, data.len());
-            tracing::error!("{err}");
-            return Err(Status::invalid_argument(&err));
+        let digest = &name.digest;
+        if writer.size != digest.size_bytes() {
+            let err = format!(
+                "expected {} bytes but received {}",
+                digest.size_bytes(),
+                writer.size
+            );
+            writer.abort();
+            return Err(Error::invalid(&err));
         }
 
-        tracing::info!("Writing {}", name.hash);
+        let hash = std::mem::replace(&mut writer.hasher, Hasher::new(digest.function()))
+            .finish()
+            .to_string();
 
-        let mut reader = std::io::Cursor::new(&data);
+        if hash != digest.hash() {
+            let err = format!("expected hash {} but data hashes to {hash}", digest.hash());
+            writer.abort();
+            return Err(Error::invalid(&err));
+        }
 
-        let mut writer = self.store.write().map_err(|err| {
-            tracing::error!("Failed to open file for writing: {err}");
-            Status::internal(err.to_string())
-        })?;
+        if let Err(err) = writer.writer.flush() {
+            writer.abort();
+            return Err(Error::io(err));
+        }
 
-        std::io::copy(&mut reader, &mut writer).map_err(|err| {
-            tracing::error!("Failed to write file: {err}");
-            Status::internal(err.to_string())
-        })?;
+        writer.writer.seal(&digest.blob_name())
+    }
 
-        writer.seal(&name.hash).map_err(|err| {
-            tracing::error!("Failed to seal file after writing: {err}");
-            Status::internal(err.to_string())
-        })?;
+    fn abort(self) {
+        self.finish().0.abort();
+    }
+}
 
-        Ok(Response::new(WriteResponse {
-            committed_size: data.len() as i64,
-        }))
+impl<W: Write> fmt::Debug for PendingUpload<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingUpload")
+            .field("committed", &self.committed)
+            .field("last_active", &self.last_active)
+            .finish()
     }
+}
 
-    async fn query_write_status(
-        &self,
-        req: Request<QueryWriteStatusRequest>,
-    ) -> Result<Response<QueryWriteStatusResponse>, Status> {
-        let req = req.into_inner();
-        tracing::info!("ByteStream::query_write_status {req:?}");
-        Err(Status::internal("not implemented"))
+/// Writes uncompressed blob data into the store, hashing it on the way.
+struct BlobWriter<W> {
+    writer: W,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl<W: WriteHandle> BlobWriter<W> {
+    fn new(writer: W, function: DigestFunction) -> Self {
+        Self {
+            writer,
+            hasher: Hasher::new(function),
+            size: 0,
+        }
+    }
+
+    fn abort(self) {
+        if let Err(err) = self.writer.abort() {
+            tracing::error!("Failed to discard upload: {err}");
+        }
+    }
+}
+
+impl<W: Write> Write for BlobWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.write_all(&buf[..written])?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
 }
 
+/// Wrap a blob in a reader that compresses it on the fly.
+fn compress_reader(
+    compressor: Compressor,
+    blob: Box<dyn Read + Send>,
+) -> Result<Box<dyn Read + Send>, Error> {
+    match compressor {
+        Compressor::Identity => Ok(blob),
+        Compressor::Zstd => {
+            let encoder = zstd::stream::read::Encoder::new(blob, compression::ZSTD_LEVEL)
+                .map_err(Error::io)?;
+            Ok(Box::new(encoder))
+        }
+        _ => Err(Error::invalid("unsupported compressor")),
+    }
+}
+
+/// Copy the data of an upload into `upload` as it arrives, starting with the
+/// `first` request. Returns the upload, along with whether the client finished
+/// it or just stopped sending data so that it can be resumed later. If the
+/// upload fails it's dropped, which discards it.
+async fn receive_upload<W, R>(
+    stream: &mut R,
+    first: WriteRequest,
+    name: &ResourceName,
+    mut upload: PendingUpload<W>,
+) -> Result<(PendingUpload<W>, bool), Error>
+where
+    W: WriteHandle + Send + 'static,
+    R: Stream<Item = Result<WriteRequest, Status>> + Unpin,
+{
+    let mut next = Some(first);
+
+    while let Some(req) = next.take() {
+        if req.write_offset != upload.committed as i64 {
+            return Err(Error::invalid(&format!(
+                "write offset {} does not match committed size {}",
+                req.write_offset, upload.committed
+            )));
+        }
+
+        // Writing may have to wait for the disk.
+        let written = tokio::task::spawn_blocking(move || {
+            let written = upload.write(&req.data);
+            written.map(|()| upload)
+        });
+
+        upload = written
+            .await
+            .map_err(Error::boxed)?
+            .map_err(|err| match name.compressor {
+                Compressor::Identity => Error::io(err),
+                _ => Error::invalid(&format!("invalid compressed data: {err}")),
+            })?;
+
+        if upload.size() > name.digest.size_bytes() {
+            let err = format!(
+                "expected {} bytes but received more",
+                name.digest.size_bytes()
+            );
+            return Err(Error::invalid(&err));
+        }
+
+        if req.finish_write {
+            return Ok((upload, true));
+        }
+
+        next = match stream.next().await {
+            Some(Ok(req)) => Some(req),
+            Some(Err(status)) => {
+                tracing::warn!("Upload of {} interrupted: {status}", name.digest.hash());
+                None
+            }
+            None => None,
+        };
+    }
+
+    Ok((upload, false))
+}
+
+fn out_of_range(offset: impl fmt::Display, size: u64) -> Status {
+    Status::out_of_range(format!(
+        "read offset {offset} is outside of blob of size {size}"
+    ))
+}
+
+/// Parse `blobs/{hash}/{size}` or `compressed-blobs/{compressor}/{hash}/{size}`
+/// from the start of `parts`, returning whatever follows it. The hash can be
+/// preceded by the name of its digest function. The hash and size are checked
+/// to be well-formed, since the hash is used to find the blob.
+fn parse_blob_path<'a>(
+    parts: &'a [&'a str],
+) -> Result<(Compressor, BlobDigest, &'a [&'a str]), Error> {
+    let (compressor, rest) = match parts {
+        ["blobs", rest @ ..] => (Compressor::Identity, rest),
+        ["compressed-blobs", compressor, rest @ ..] => (compression::parse(compressor)?, rest),
+        _ => return Err(Error::invalid("expected blobs or compressed-blobs")),
+    };
+
+    let named = rest
+        .first()
+        .and_then(|name| DigestFunction::from_name(name));
+    let rest = if named.is_some() { &rest[1..] } else { rest };
+
+    let [hash, size, rest @ ..] = rest else {
+        return Err(Error::invalid("expected hash and size"));
+    };
+
+    let function = match named {
+        Some(function) => function,
+        None => DigestFunction::infer(hash)?,
+    };
+
+    let size = i64::from_str(size).map_err(|_| Error::invalid("invalid size in resource name"))?;
+    let digest = BlobDigest::new(function, hash, size)?;
+
+    Ok((compressor, digest, rest))
+}
+
+/// Name of a blob to download, in the form
+/// `{instance_name}/blobs/{hash}/{size}` or
+/// `{instance_name}/compressed-blobs/{compressor}/{hash}/{size}`. The instance
+/// name is optional and may itself contain slashes.
+#[derive(Debug, Clone, PartialEq)]
+struct BlobResourceName {
+    pub instance_name: String,
+    pub compressor: Compressor,
+    pub digest: BlobDigest,
+}
+
+impl BlobResourceName {
+    pub fn parse(resource_name: &str) -> Result<Self, Error> {
+        let parts = resource_name.split('/').collect::<Vec<_>>();
+
+        let blobs = parts
+            .iter()
+            .position(|part| *part == "blobs" || *part == "compressed-blobs")
+            .ok_or_else(|| Error::invalid(&format!("not a blob: {resource_name}")))?;
+
+        let (compressor, digest, rest) = parse_blob_path(&parts[blobs..])
+            .map_err(|err| Error::invalid(&format!("{err}: {resource_name}")))?;
+
+        if !rest.is_empty() {
+            return Err(Error::invalid(&format!(
+                "unexpected trailing components: {resource_name}"
+            )));
+        }
+
+        Ok(BlobResourceName {
+            instance_name: parts[..blobs].join("/"),
+            compressor,
+            digest,
+        })
+    }
+}
+
+/// Name of a blob to upload, in the form
+/// `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}` or
+/// `{instance_name}/uploads/{uuid}/compressed-blobs/{compressor}/{hash}/{size}`.
+/// The instance name is optional and may itself contain slashes, and there can
+/// be trailing components after the size.
 #[derive(Debug, Clone, PartialEq)]
 struct ResourceName {
+    pub instance_name: String,
     pub uuid: String,
-    pub hash: String,
-    pub size: u64,
+    pub compressor: Compressor,
+    pub digest: BlobDigest,
 }
 
 impl ResourceName {
-    // `resource_name` is `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}`,
-    // where `{instance_name}` is optional, and there can be trailing URL
-    // components after {size}.
     pub fn parse(resource_name: &str) -> Result<Self, Error> {
-        let parts = resource_name.split("/").collect::<Vec<_>>();
+        let parts = resource_name.split('/').collect::<Vec<_>>();
+
+        let uploads = parts
+            .iter()
+            .position(|part| *part == "uploads")
+            .ok_or_else(|| Error::invalid(&format!("not an upload: {resource_name}")))?;
 
-        if parts.len() < 5 {
+        let [uuid, rest @ ..] = &parts[uploads + 1..] else {
             return Err(Error::invalid(&format!(
-                "must have more parts: {resource_name}"
+                "missing upload id: {resource_name}"
             )));
+        };
+
+        let (compressor, digest, _) = parse_blob_path(rest)
+            .map_err(|err| Error::invalid(&format!("{err}: {resource_name}")))?;
+
+        Ok(ResourceName {
+            instance_name: parts[..uploads].join("/"),
+            uuid: uuid.to_string(),
+            compressor,
+            digest,
+        })
+    }
+
+    /// The committed size reported for an upload of a blob that is already
+    /// stored. The size of compressed data isn't known, so it's reported as
+    /// `-1` in that case, as the protocol requires.
+    pub fn complete_size(&self) -> i64 {
+        match self.compressor {
+            Compressor::Identity => self.digest.size_bytes() as i64,
+            _ => -1,
         }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::hash;
+    use storage::mem::MemStore;
+    use tonic::Code;
+
+    fn service(store: &MemStore) -> ByteStreamService<MemStore> {
+        ByteStreamService::new(store.clone(), Duration::from_secs(60), 4)
+    }
 
-        if parts[0] != "uploads" {
-            return Err(Error::invalid("resource name does not begin with uploads"));
+    fn upload_name(data: &[u8]) -> String {
+        format!("uploads/1234/blobs/{}/{}", hash::sha256(data), data.len())
+    }
+
+    fn write_request(resource_name: &str, offset: i64, data: &[u8], finish: bool) -> WriteRequest {
+        WriteRequest {
+            resource_name: resource_name.to_string(),
+            write_offset: offset,
+            finish_write: finish,
+            data: data.to_vec(),
         }
+    }
 
-        let size =
-            u64::from_str(parts[4]).map_err(|_| Error::invalid("invalid size in resource name"))?;
+    async fn write(
+        service: &ByteStreamService<MemStore>,
+        reqs: Vec<WriteRequest>,
+    ) -> Result<WriteResponse, Status> {
+        let stream = tokio_stream::iter(reqs.into_iter().map(Ok));
+        service.receive_write(stream).await
+    }
 
-        Ok(ResourceName {
-            uuid: parts[1].to_string(),
-            hash: parts[3].to_string(),
-            size,
-        })
+    fn stored(store: &MemStore, data: &[u8]) -> Option<Vec<u8>> {
+        let mut reader = store.read(&hash::sha256(data).blob_name()).ok()?;
+        let mut stored = vec![];
+        reader.read_to_end(&mut stored).unwrap();
+        Some(stored)
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let name = upload_name(b"hello world");
+
+        let res = write(
+            &service,
+            vec![
+                write_request(&name, 0, b"hello ", false),
+                write_request(&name, 6, b"world", true),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.committed_size, 11);
+        assert_eq!(stored(&store, b"hello world").unwrap(), b"hello world");
+
+        // Uploading a blob that is already stored finishes straight away.
+        let res = write(&service, vec![write_request(&name, 0, b"hello", false)])
+            .await
+            .unwrap();
+        assert_eq!(res.committed_size, 11);
+
+        // Data that doesn't match the digest is discarded.
+        let name = upload_name(b"foo");
+        for data in [&b"bar"[..], b"fooo"] {
+            let err = write(&service, vec![write_request(&name, 0, data, true)])
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+            assert!(stored(&store, b"foo").is_none());
+        }
+
+        let err = write(&service, vec![write_request(&name, 1, b"oo", true)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    async fn query(
+        service: &ByteStreamService<MemStore>,
+        resource_name: &str,
+    ) -> Result<QueryWriteStatusResponse, Status> {
+        let req = QueryWriteStatusRequest {
+            resource_name: resource_name.to_string(),
+        };
+
+        service
+            .query_write_status(Request::new(req))
+            .await
+            .map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn test_resume_write() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let name = upload_name(b"hello world");
+
+        let err = query(&service, &name).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        let err = write(&service, vec![write_request(&name, 6, b"world", true)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        // The stream ends before the upload is finished.
+        let res = write(&service, vec![write_request(&name, 0, b"hello ", false)])
+            .await
+            .unwrap();
+        assert_eq!(res.committed_size, 6);
+        let status = query(&service, &name).await.unwrap();
+        assert_eq!((status.committed_size, status.complete), (6, false));
+
+        let err = write(&service, vec![write_request(&name, 4, b"o world", true)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let res = write(&service, vec![write_request(&name, 6, b"world", true)])
+            .await
+            .unwrap();
+        assert_eq!(res.committed_size, 11);
+        let status = query(&service, &name).await.unwrap();
+        assert_eq!((status.committed_size, status.complete), (11, true));
+        assert!(stored(&store, b"hello world").is_some());
+
+        // Starting again from scratch discards what was uploaded so far.
+        let name = upload_name(b"foo");
+        write(&service, vec![write_request(&name, 0, b"fx", false)])
+            .await
+            .unwrap();
+        write(&service, vec![write_request(&name, 0, b"foo", true)])
+            .await
+            .unwrap();
+        assert!(stored(&store, b"foo").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expire_idle_uploads() {
+        let store = MemStore::new();
+        let service = ByteStreamService::new(store.clone(), Duration::ZERO, 4);
+        let name = upload_name(b"hello");
+
+        write(&service, vec![write_request(&name, 0, b"he", false)])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        let err = query(&service, &name).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    fn put(store: &MemStore, data: &[u8]) -> String {
+        let mut writer = store.write().unwrap();
+        writer.write_all(data).unwrap();
+        writer.seal(&hash::sha256(data).blob_name()).unwrap();
+        format!("blobs/{}/{}", hash::sha256(data), data.len())
+    }
+
+    /// Read a blob, checking that it's sent in chunks of the configured size.
+    async fn read(
+        service: &ByteStreamService<MemStore>,
+        resource_name: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<u8>, Status> {
+        let req = ReadRequest {
+            resource_name: resource_name.to_string(),
+            read_offset: offset,
+            read_limit: limit,
+        };
+
+        let mut stream = service.read(Request::new(req)).await?.into_inner();
+        let mut data = vec![];
+        while let Some(res) = stream.next().await {
+            let chunk = res?.data;
+            assert!(chunk.len() <= service.read_chunk_size);
+            data.extend(chunk);
+        }
+
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn test_ranged_read() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let name = put(&store, b"hello world");
+
+        assert_eq!(read(&service, &name, 0, 0).await.unwrap(), b"hello world");
+        assert_eq!(read(&service, &name, 6, 0).await.unwrap(), b"world");
+        assert_eq!(read(&service, &name, 2, 7).await.unwrap(), b"llo wor");
+        assert_eq!(read(&service, &name, 6, 100).await.unwrap(), b"world");
+        assert_eq!(read(&service, &name, 11, 0).await.unwrap(), b"");
+
+        for offset in [-1, 12] {
+            let err = read(&service, &name, offset, 0).await.unwrap_err();
+            assert_eq!(err.code(), Code::OutOfRange);
+        }
+
+        let err = read(&service, &name, 0, -1).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let wrong_size = format!("blobs/{}/10", hash::sha256(b"hello world"));
+        let err = read(&service, &wrong_size, 0, 0).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let missing = format!("blobs/{}/7", hash::sha256(b"missing"));
+        let err = read(&service, &missing, 0, 0).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_round_trip() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let data = b"hello hello hello hello world".repeat(10);
+        let compressed = zstd::encode_all(&data[..], compression::ZSTD_LEVEL).unwrap();
+        let path = format!(
+            "compressed-blobs/zstd/{}/{}",
+            hash::sha256(&data),
+            data.len()
+        );
+
+        let name = format!("uploads/1234/{path}");
+        let (first, rest) = compressed.split_at(5);
+        let res = write(
+            &service,
+            vec![
+                write_request(&name, 0, first, false),
+                write_request(&name, first.len() as i64, rest, true),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.committed_size, compressed.len() as i64);
+        assert_eq!(stored(&store, &data).unwrap(), data);
+
+        // The size of compressed data isn't known once it's stored.
+        let res = write(&service, vec![write_request(&name, 0, first, false)])
+            .await
+            .unwrap();
+        assert_eq!(res.committed_size, -1);
+
+        let read_compressed = read(&service, &path, 0, 0).await.unwrap();
+        assert_eq!(zstd::decode_all(&read_compressed[..]).unwrap(), data);
+        let tail = read(&service, &path, 5, 0).await.unwrap();
+        assert_eq!(tail, read_compressed[5..]);
+
+        let empty = format!("compressed-blobs/zstd/{}/0", hash::sha256(b""));
+        let read_empty = read(&service, &empty, 0, 0).await.unwrap();
+        assert!(zstd::decode_all(&read_empty[..]).unwrap().is_empty());
+
+        let name = format!(
+            "uploads/1234/compressed-blobs/zstd/{}/3",
+            hash::sha256(b"foo")
+        );
+        let err = write(&service, vec![write_request(&name, 0, b"foo", true)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert!(stored(&store, b"foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_names() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let hash = hash::sha256(b"foo").to_string();
+        let malformed = [
+            "blobs/../../etc/passwd/3".to_string(),
+            "blobs/..%2F..%2Fetc%2Fpasswd/3".to_string(),
+            format!("blobs/{}/3", hash.to_uppercase()),
+            format!("blobs/{}/3", &hash[1..]),
+            format!("blobs/{hash}/-1"),
+            format!("compressed-blobs/zstd/../{hash}/3"),
+        ];
+
+        for path in malformed {
+            let err = read(&service, &path, 0, 0).await.unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "{path}");
+
+            let name = format!("uploads/1234/{path}");
+            let err = write(&service, vec![write_request(&name, 0, b"foo", true)])
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "{path}");
+        }
+
+        assert!(store.list().unwrap().next().is_none());
     }
 }
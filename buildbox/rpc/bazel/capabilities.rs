@@ -1,3 +1,7 @@
+use super::compression::SUPPORTED_COMPRESSORS;
+use super::instance::Instances;
+use super::{absolute_symlink_strategy, into_status, MAX_BATCH_TOTAL_SIZE_BYTES};
+use common::hash::DigestFunction;
 use proto::bazel::exec::{
     digest_function, ActionCacheUpdateCapabilities, CacheCapabilities, Capabilities,
     ExecutionCapabilities, GetCapabilitiesRequest, ServerCapabilities,
@@ -6,25 +10,59 @@ use proto::bazel::semver::SemVer;
 use tonic::{Request, Response, Status};
 
 #[derive(Default, Debug)]
-pub struct CapabilitiesService {}
+pub struct CapabilitiesService {
+    absolute_symlinks: bool,
+    /// Whether the action cache of each instance is read-only.
+    read_only: Instances<bool>,
+}
+
+impl CapabilitiesService {
+    /// Advertise whether input symlinks may have absolute targets, which
+    /// depends on the executor.
+    pub fn with_absolute_symlinks(mut self, allowed: bool) -> Self {
+        self.absolute_symlinks = allowed;
+        self
+    }
+
+    /// Advertise the instance called `name`, whose action cache clients can
+    /// only update if it isn't `read_only`.
+    pub fn with_instance(mut self, name: &str, read_only: bool) -> Self {
+        self.read_only.insert(name, read_only);
+        self
+    }
+}
 
 #[async_trait::async_trait]
 impl Capabilities for CapabilitiesService {
     async fn get_capabilities(
         &self,
-        _req: Request<GetCapabilitiesRequest>,
+        req: Request<GetCapabilitiesRequest>,
     ) -> Result<Response<ServerCapabilities>, Status> {
-        tracing::info!("Capabilities::get_capabilities");
+        let req = req.into_inner();
+        tracing::info!("Capabilities::get_capabilities {:?}", req.instance_name);
+        let read_only = *self.read_only.get(&req.instance_name).map_err(into_status)?;
+        let digest_functions = DigestFunction::ALL
+            .map(|function| digest_function::Value::from(function).into())
+            .to_vec();
+
         let cap = ServerCapabilities {
             cache_capabilities: Some(CacheCapabilities {
-                digest_function: vec![digest_function::Value::Sha256.into()],
+                digest_function: digest_functions.clone(),
                 action_cache_update_capabilities: Some(ActionCacheUpdateCapabilities {
-                    update_enabled: true,
+                    update_enabled: !read_only,
                 }),
+                max_batch_total_size_bytes: MAX_BATCH_TOTAL_SIZE_BYTES,
+                supported_compressors: SUPPORTED_COMPRESSORS.map(Into::into).to_vec(),
+                supported_batch_update_compressors: SUPPORTED_COMPRESSORS.map(Into::into).to_vec(),
+                symlink_absolute_path_strategy: absolute_symlink_strategy(self.absolute_symlinks)
+                    .into(),
+                split_blob_support: true,
+                splice_blob_support: true,
                 ..Default::default()
             }),
             execution_capabilities: Some(ExecutionCapabilities {
                 digest_function: digest_function::Value::Sha256.into(),
+                digest_functions,
                 exec_enabled: true,
                 ..Default::default()
             }),
@@ -1,12 +1,31 @@
-use super::ResponseStream;
+use super::compression::{self, Compressor};
+use super::instance::Instances;
+use super::tree::DirectoryWalker;
+use super::{
+    blob_digest, blocking, into_rpc_status, into_status, required_digest, ResponseStream,
+    MAX_BATCH_TOTAL_SIZE_BYTES,
+};
+use common::hash::{self, BlobDigest, DigestFunction};
+use common::Error;
 use proto::bazel::exec::{
-    BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
-    BatchUpdateBlobsResponse, ContentAddressableStorage, FindMissingBlobsRequest,
-    FindMissingBlobsResponse, GetTreeRequest, GetTreeResponse,
+    batch_read_blobs_response, batch_update_blobs_request, batch_update_blobs_response,
+    digest_function, BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
+    BatchUpdateBlobsResponse, ContentAddressableStorage, Digest, FindMissingBlobsRequest,
+    FindMissingBlobsResponse, GetTreeRequest, GetTreeResponse, SpliceBlobRequest,
+    SpliceBlobResponse, SplitBlobRequest, SplitBlobResponse,
 };
-use storage::Store;
+use proto::google::rpc;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use storage::{chunked, Store, WriteHandle};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
+/// Number of directories returned in each `GetTree` page when the client
+/// doesn't ask for a particular page size.
+const DEFAULT_TREE_PAGE_SIZE: usize = 1000;
+
 /// The CAS (content-addressable storage) is used to store the inputs to and
 /// outputs from the execution service. Each piece of content is addressed by
 /// the digest of its binary data.
@@ -26,90 +45,819 @@ use tonic::{Request, Response, Status};
 ///
 /// For larger uploads, the client must use the `Write` method of the
 /// `ByteStream` API.
-#[derive(Debug)]
+///
+/// Instances can share a store, in which case they share their blobs too.
+#[derive(Debug, Clone)]
 pub struct ContentAddressableStorageService<S> {
-    storage: S,
+    instances: Instances<CasInstance<S>>,
 }
 
 impl<S> ContentAddressableStorageService<S>
 where
     S: Store + 'static,
 {
-    /// Create new instance of [`ContentAddressableStorageService`].
+    /// Create new instance of [`ContentAddressableStorageService`], whose
+    /// default instance keeps blobs in `storage`.
     #[must_use]
     pub fn new(storage: S) -> Self {
-        Self { storage }
+        Self {
+            instances: Instances::new(CasInstance { storage }),
+        }
+    }
+
+    /// Serve the instance called `name` from `storage`.
+    #[must_use]
+    pub fn with_instance(mut self, name: &str, storage: S) -> Self {
+        self.instances.insert(name, CasInstance { storage });
+        self
+    }
+
+    fn instance(&self, name: &str) -> Result<CasInstance<S>, Error> {
+        self.instances.get(name).cloned()
     }
 }
 
-#[async_trait::async_trait]
-impl<S> ContentAddressableStorage for ContentAddressableStorageService<S>
+/// The blobs of a single instance.
+#[derive(Debug, Clone)]
+struct CasInstance<S> {
+    storage: S,
+}
+
+impl<S> CasInstance<S>
 where
     S: Store + 'static,
 {
-    /// Determine whether a blob is in the CAS.
-    ///
-    /// Clients can use this API before uploading blobs to determine which ones
-    /// are already present in the CAS and do not need to be uploaded again.
-    async fn find_missing_blobs(
-        &self,
-        req: Request<FindMissingBlobsRequest>,
-    ) -> Result<Response<FindMissingBlobsResponse>, Status> {
-        let req = req.into_inner();
+    /// Find the blobs that aren't in the CAS.
+    fn find_missing(&self, function: i32, digests: &[Digest]) -> Result<Vec<Digest>, Error> {
         let mut missing = vec![];
 
-        for digest in &req.blob_digests {
-            let hash = &digest.hash;
-
-            let exists = match self.storage.contains(&hash) {
-                Ok(exists) => exists,
-                Err(err) => return Err(Status::internal(err.to_string())),
-            };
+        for proto_digest in digests {
+            let digest = blob_digest(function, proto_digest)?;
+            let hash = digest.hash();
 
             // Empty file digest
-            if hash == "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855" {
+            if hash == hash::compute(digest.function(), &[]).as_str() {
                 continue;
             }
 
-            if !exists {
+            if !self.storage.contains(&digest.blob_name())? {
                 tracing::info!("ContentAddressableStorage::find_missing_blobs missing hash={hash}");
-                missing.push(digest.clone());
+                missing.push(proto_digest.clone());
             } else {
                 tracing::info!("ContentAddressableStorage::find_missing_blobs found hash={hash}");
             }
         }
 
+        Ok(missing)
+    }
+
+    /// Store each blob, reporting the outcome for each of them separately.
+    fn update_blobs(
+        &self,
+        function: i32,
+        requests: &[batch_update_blobs_request::Request],
+    ) -> Vec<batch_update_blobs_response::Response> {
+        let mut responses = vec![];
+
+        for blob in requests {
+            let status = match self.update_blob(function, blob) {
+                Ok(()) => rpc::Status::default(),
+                Err(err) => {
+                    tracing::warn!("Failed to update blob {:?}: {err}", blob.digest);
+                    into_rpc_status(err)
+                }
+            };
+
+            responses.push(batch_update_blobs_response::Response {
+                digest: blob.digest.clone(),
+                status: Some(status),
+            });
+        }
+
+        responses
+    }
+
+    /// Verify a single blob against its declared digest and store it. The
+    /// digest always refers to the uncompressed data.
+    fn update_blob(
+        &self,
+        function: i32,
+        req: &batch_update_blobs_request::Request,
+    ) -> Result<(), Error> {
+        let digest = required_digest(function, req.digest.as_ref(), "digest")?;
+
+        let compressor = compression::from_i32(req.compressor)?;
+        let data = compression::decompress(compressor, &req.data, digest.size_bytes())?;
+
+        if data.len() as u64 != digest.size_bytes() {
+            return Err(Error::invalid(&format!(
+                "expected {} bytes but received {}",
+                digest.size_bytes(),
+                data.len()
+            )));
+        }
+
+        let hash = hash::compute(digest.function(), &data).to_string();
+        if hash != digest.hash() {
+            return Err(Error::invalid(&format!(
+                "expected hash {} but data hashes to {hash}",
+                digest.hash()
+            )));
+        }
+
+        let mut writer = self.storage.write()?;
+        writer.write_all(&data).map_err(Error::io)?;
+        writer.flush().map_err(Error::io)?;
+        writer.seal(&digest.blob_name())
+    }
+
+    /// Read a single blob, checking that it has the size the client expects.
+    /// No more than one byte past that size is read, so that a blob that is
+    /// bigger than the client claims can't be pulled into memory whole.
+    fn read_blob(&self, function: i32, digest: &Digest) -> Result<Vec<u8>, Error> {
+        let digest = blob_digest(function, digest)?;
+        if digest.size_bytes() == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut data = vec![];
+        let reader = self.storage.read(&digest.blob_name())?;
+        reader
+            .take(digest.size_bytes() + 1)
+            .read_to_end(&mut data)
+            .map_err(Error::io)?;
+
+        if data.len() as u64 > digest.size_bytes() {
+            return Err(Error::invalid(&format!(
+                "expected {} bytes but blob is bigger",
+                digest.size_bytes()
+            )));
+        }
+
+        if data.len() as u64 != digest.size_bytes() {
+            return Err(Error::invalid(&format!(
+                "expected {} bytes but blob has {}",
+                digest.size_bytes(),
+                data.len()
+            )));
+        }
+
+        Ok(data)
+    }
+
+    /// Read each blob, reporting the outcome for each of them separately.
+    fn read_blobs(
+        &self,
+        function: i32,
+        digests: &[Digest],
+        compressor: Compressor,
+    ) -> Vec<batch_read_blobs_response::Response> {
+        let mut responses = vec![];
+
+        for digest in digests {
+            let read = self.read_blob(function, digest).and_then(|data| {
+                compression::compress(compressor, &data).map(|data| data.into_owned())
+            });
+
+            let (data, status) = match read {
+                Ok(data) => (data, rpc::Status::default()),
+                Err(err) => {
+                    tracing::warn!("Failed to read blob {}: {err}", digest.hash);
+                    (vec![], into_rpc_status(err))
+                }
+            };
+
+            responses.push(batch_read_blobs_response::Response {
+                digest: Some(digest.clone()),
+                data,
+                status: Some(status),
+                compressor: compressor.into(),
+            });
+        }
+
+        responses
+    }
+
+    /// Find the chunks that a blob is made of, splitting it up and storing
+    /// the chunks if the store keeps it whole. Chunked stores only chunk with
+    /// SHA256, so blobs of other functions are always split here.
+    fn split_blob(&self, digest: &BlobDigest) -> Result<Vec<Digest>, Error> {
+        let name = digest.blob_name();
+        if digest.function() == DigestFunction::Sha256 {
+            if let Some(chunks) = self.storage.chunks(&name)? {
+                return Ok(chunks);
+            }
+        }
+
+        let reader = self.storage.read(&name)?;
+        chunked::split(&self.storage, digest.function(), reader)
+    }
+
+    /// Store a blob made of chunks that are already in the CAS, checking that
+    /// they add up to the blob's digest. The chunks must have been made with
+    /// the same function as the blob.
+    fn splice_blob(&self, digest: &BlobDigest, chunks: &[Digest]) -> Result<(), Error> {
+        let function = digest.function();
+        let chunks = chunks
+            .iter()
+            .map(|chunk| BlobDigest::from_proto(function, chunk))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let total_size: u64 = chunks.iter().map(|chunk| chunk.size_bytes()).sum();
+        if total_size != digest.size_bytes() {
+            return Err(Error::invalid(&format!(
+                "expected {} bytes but chunks add up to {total_size}",
+                digest.size_bytes()
+            )));
+        }
+
+        let mut writer = self.storage.write()?;
+        let mut hasher = hash::Hasher::new(function);
+        let mut buf = vec![0; 64 * 1024];
+
+        for chunk in &chunks {
+            let mut reader = self.storage.read(&chunk.blob_name())?;
+            let mut size = 0;
+
+            loop {
+                let read = reader.read(&mut buf).map_err(Error::io)?;
+                if read == 0 {
+                    break;
+                }
+
+                writer.write_all(&buf[..read]).map_err(Error::io)?;
+                hasher.write_all(&buf[..read]).map_err(Error::io)?;
+                size += read as u64;
+            }
+
+            if size != chunk.size_bytes() {
+                writer.abort()?;
+                return Err(Error::invalid(&format!(
+                    "chunk {} has {size} bytes rather than {}",
+                    chunk.hash(),
+                    chunk.size_bytes()
+                )));
+            }
+        }
+
+        let hash = hasher.finish().to_string();
+        if hash != digest.hash() {
+            writer.abort()?;
+            return Err(Error::invalid(&format!(
+                "expected hash {} but chunks hash to {hash}",
+                digest.hash()
+            )));
+        }
+
+        writer.flush().map_err(Error::io)?;
+        writer.seal(&digest.blob_name())
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> ContentAddressableStorage for ContentAddressableStorageService<S>
+where
+    S: Store + 'static,
+{
+    /// Determine whether a blob is in the CAS.
+    ///
+    /// Clients can use this API before uploading blobs to determine which ones
+    /// are already present in the CAS and do not need to be uploaded again.
+    async fn find_missing_blobs(
+        &self,
+        req: Request<FindMissingBlobsRequest>,
+    ) -> Result<Response<FindMissingBlobsResponse>, Status> {
+        let req = req.into_inner();
+
+        let cas = self.instance(&req.instance_name).map_err(into_status)?;
+        let missing =
+            blocking(move || cas.find_missing(req.digest_function, &req.blob_digests)).await?;
+
         Ok(Response::new(FindMissingBlobsResponse {
             missing_blob_digests: missing,
         }))
     }
 
+    /// Upload many blobs at once.
+    ///
+    /// Each blob is verified against its digest independently, and the result
+    /// of storing it is reported with its own status in the response.
     async fn batch_update_blobs(
         &self,
         req: Request<BatchUpdateBlobsRequest>,
     ) -> Result<Response<BatchUpdateBlobsResponse>, Status> {
         let req = req.into_inner();
-        tracing::info!("ContentAddressableStorage::batch_update_blobs {req:?}");
-        Ok(Response::new(BatchUpdateBlobsResponse::default()))
+        tracing::info!(
+            "ContentAddressableStorage::batch_update_blobs count={}",
+            req.requests.len()
+        );
+
+        let cas = self.instance(&req.instance_name).map_err(into_status)?;
+        let responses =
+            blocking(move || Ok(cas.update_blobs(req.digest_function, &req.requests))).await?;
+
+        Ok(Response::new(BatchUpdateBlobsResponse { responses }))
     }
 
+    /// Download many blobs at once.
+    ///
+    /// The combined size of the requested blobs must not exceed the limit
+    /// advertised in the cache capabilities. Blobs that can't be read are
+    /// reported with their own status rather than failing the whole request.
+    /// If the client accepts zstd, every blob is sent compressed.
     async fn batch_read_blobs(
         &self,
         req: Request<BatchReadBlobsRequest>,
     ) -> Result<Response<BatchReadBlobsResponse>, Status> {
         let req = req.into_inner();
-        tracing::info!("ContentAddressableStorage::batch_read_blobs {req:?}");
-        Ok(Response::new(BatchReadBlobsResponse::default()))
+        tracing::info!(
+            "ContentAddressableStorage::batch_read_blobs count={}",
+            req.digests.len()
+        );
+
+        // The sizes are only added up once every digest is known to be valid,
+        // so that negative sizes can't be used to get past the limit.
+        let mut total_size: u64 = 0;
+        for digest in &req.digests {
+            let digest = blob_digest(req.digest_function, digest).map_err(into_status)?;
+            total_size = total_size.saturating_add(digest.size_bytes());
+        }
+
+        if total_size > MAX_BATCH_TOTAL_SIZE_BYTES as u64 {
+            return Err(Status::invalid_argument(format!(
+                "requested {total_size} bytes but the limit is {MAX_BATCH_TOTAL_SIZE_BYTES}"
+            )));
+        }
+
+        let compressor = if req
+            .acceptable_compressors
+            .contains(&(Compressor::Zstd as i32))
+        {
+            Compressor::Zstd
+        } else {
+            Compressor::Identity
+        };
+
+        let cas = self.instance(&req.instance_name).map_err(into_status)?;
+        let responses =
+            blocking(move || Ok(cas.read_blobs(req.digest_function, &req.digests, compressor)))
+                .await?;
+
+        Ok(Response::new(BatchReadBlobsResponse { responses }))
+    }
+
+    /// Split a blob into chunks, so that the client only needs to download
+    /// the chunks it doesn't already have.
+    ///
+    /// Blobs are split with content-defined chunking, so blobs that only
+    /// differ slightly share most of their chunks. Every chunk is stored in
+    /// the CAS as a blob of its own.
+    async fn split_blob(
+        &self,
+        req: Request<SplitBlobRequest>,
+    ) -> Result<Response<SplitBlobResponse>, Status> {
+        let req = req.into_inner();
+        tracing::info!(
+            "ContentAddressableStorage::split_blob {:?}",
+            req.blob_digest
+        );
+
+        let digest = required_digest(req.digest_function, req.blob_digest.as_ref(), "blob digest")
+            .map_err(into_status)?;
+        let digest_function = digest_function::Value::from(digest.function()).into();
+
+        let cas = self.instance(&req.instance_name).map_err(into_status)?;
+        let chunk_digests = blocking(move || cas.split_blob(&digest)).await?;
+
+        Ok(Response::new(SplitBlobResponse {
+            chunk_digests,
+            digest_function,
+        }))
+    }
+
+    /// Put a blob together from chunks that are already in the CAS, so that
+    /// the client only needs to upload the chunks that are missing.
+    async fn splice_blob(
+        &self,
+        req: Request<SpliceBlobRequest>,
+    ) -> Result<Response<SpliceBlobResponse>, Status> {
+        let req = req.into_inner();
+        tracing::info!(
+            "ContentAddressableStorage::splice_blob {:?}",
+            req.blob_digest
+        );
+
+        let digest = required_digest(req.digest_function, req.blob_digest.as_ref(), "blob digest")
+            .map_err(into_status)?;
+
+        let cas = self.instance(&req.instance_name).map_err(into_status)?;
+        let blob_digest = digest.clone();
+        blocking(move || cas.splice_blob(&digest, &req.chunk_digests)).await?;
+
+        Ok(Response::new(SpliceBlobResponse {
+            blob_digest: Some(blob_digest.into()),
+        }))
     }
 
     type GetTreeStream = ResponseStream<Result<GetTreeResponse, Status>>;
 
+    /// Fetch the entire directory tree rooted at a node.
+    ///
+    /// Directories are returned breadth-first in pages of at most `page_size`
+    /// directories, and each distinct directory is only returned once. The
+    /// page token is the number of directories already returned, so a client
+    /// can resume an interrupted request from the last page it received.
     async fn get_tree(
         &self,
         req: Request<GetTreeRequest>,
     ) -> Result<Response<Self::GetTreeStream>, Status> {
         let req = req.into_inner();
         tracing::info!("ContentAddressableStorage::get_tree {req:?}");
-        todo!()
+
+        let root = req
+            .root_digest
+            .ok_or_else(|| Status::invalid_argument("missing root digest"))?;
+        let function = blob_digest(req.digest_function, &root)
+            .map_err(into_status)?
+            .function();
+
+        let offset = if req.page_token.is_empty() {
+            0
+        } else {
+            usize::from_str(&req.page_token)
+                .map_err(|_| Status::invalid_argument("invalid page token"))?
+        };
+
+        let page_size = match req.page_size {
+            size if size > 0 => size as usize,
+            _ => DEFAULT_TREE_PAGE_SIZE,
+        };
+
+        let storage = self
+            .instance(&req.instance_name)
+            .map_err(into_status)?
+            .storage;
+        let (tx, rx) = mpsc::channel(1);
+
+        // Walking the tree reads every directory from the store.
+        tokio::task::spawn_blocking(move || {
+            let mut sent = offset;
+            let mut directories = vec![];
+
+            for entry in DirectoryWalker::new(&storage, function, &root)
+                .unique()
+                .skip(offset)
+            {
+                let dir = match entry {
+                    Ok(entry) => entry.dir,
+                    Err(err) => {
+                        let _ = tx.blocking_send(Err(into_status(err)));
+                        return;
+                    }
+                };
+
+                directories.push(dir);
+                if directories.len() < page_size {
+                    continue;
+                }
+
+                sent += directories.len();
+                let page = GetTreeResponse {
+                    directories: std::mem::take(&mut directories),
+                    next_page_token: sent.to_string(),
+                };
+
+                if tx.blocking_send(Ok(page)).is_err() {
+                    return;
+                }
+            }
+
+            let page = GetTreeResponse {
+                directories,
+                next_page_token: String::new(),
+            };
+
+            let _ = tx.blocking_send(Ok(page));
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prost::Message;
+    use proto::bazel::exec::{Directory, DirectoryNode, FileNode};
+    use storage::mem::MemStore;
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+
+    /// Store `data` and return its digest.
+    fn put(store: &MemStore, data: &[u8]) -> Digest {
+        let hash = hash::sha256(data);
+        let mut writer = store.write().unwrap();
+        writer.write_all(data).unwrap();
+        writer.seal(&hash.blob_name()).unwrap();
+
+        Digest {
+            hash: hash.to_string(),
+            size_bytes: data.len() as i64,
+        }
+    }
+
+    async fn batch_read(
+        service: &ContentAddressableStorageService<MemStore>,
+        digests: Vec<Digest>,
+    ) -> Result<BatchReadBlobsResponse, Status> {
+        let req = BatchReadBlobsRequest {
+            digests,
+            ..Default::default()
+        };
+
+        service
+            .batch_read_blobs(Request::new(req))
+            .await
+            .map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn test_batch_update_blobs() {
+        let store = MemStore::new();
+        let service = ContentAddressableStorageService::new(store.clone());
+        let digest = |data: &[u8]| Digest {
+            hash: hash::sha256(data).to_string(),
+            size_bytes: data.len() as i64,
+        };
+        let request = |digest: Digest, data: &[u8]| batch_update_blobs_request::Request {
+            digest: Some(digest),
+            data: data.to_vec(),
+            ..Default::default()
+        };
+
+        let req = BatchUpdateBlobsRequest {
+            requests: vec![
+                request(digest(b"foo"), b"foo"),
+                request(digest(b"bar"), b"baz"),
+                request(digest(b"qux"), b"quxx"),
+            ],
+            ..Default::default()
+        };
+        let res = service
+            .batch_update_blobs(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let codes = res
+            .responses
+            .iter()
+            .map(|res| Code::from_i32(res.status.as_ref().unwrap().code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [Code::Ok, Code::InvalidArgument, Code::InvalidArgument]
+        );
+
+        assert!(store.contains(&digest(b"foo").hash).unwrap());
+        assert!(!store.contains(&digest(b"bar").hash).unwrap());
+        assert!(!store.contains(&digest(b"qux").hash).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_batch_read_blobs_limit() {
+        let store = MemStore::new();
+        let service = ContentAddressableStorageService::new(store.clone());
+        let digest = put(&store, b"foo");
+
+        let res = batch_read(&service, vec![digest.clone()]).await.unwrap();
+        assert_eq!(res.responses[0].data, b"foo");
+
+        // Negative sizes can't make up for sizes over the limit.
+        let negative = Digest {
+            size_bytes: -MAX_BATCH_TOTAL_SIZE_BYTES,
+            ..digest.clone()
+        };
+        let large = Digest {
+            size_bytes: MAX_BATCH_TOTAL_SIZE_BYTES + 1,
+            ..digest.clone()
+        };
+        let err = batch_read(&service, vec![negative, large.clone()])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let huge = Digest {
+            size_bytes: i64::MAX,
+            ..digest
+        };
+        let err = batch_read(&service, vec![huge.clone(), huge])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err = batch_read(&service, vec![large]).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_batch_compressed() {
+        let store = MemStore::new();
+        let service = ContentAddressableStorageService::new(store.clone());
+        let data = b"hello hello hello hello world".repeat(10);
+        let digest = Digest {
+            hash: hash::sha256(&data).to_string(),
+            size_bytes: data.len() as i64,
+        };
+
+        let req = BatchUpdateBlobsRequest {
+            requests: vec![batch_update_blobs_request::Request {
+                digest: Some(digest.clone()),
+                data: zstd::encode_all(&data[..], compression::ZSTD_LEVEL).unwrap(),
+                compressor: Compressor::Zstd as i32,
+            }],
+            ..Default::default()
+        };
+        let res = service
+            .batch_update_blobs(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(res.responses[0].status.as_ref().unwrap().code, 0);
+        assert!(store.contains(&digest.hash).unwrap());
+
+        let req = BatchReadBlobsRequest {
+            digests: vec![digest],
+            acceptable_compressors: vec![Compressor::Zstd as i32],
+            ..Default::default()
+        };
+        let res = service
+            .batch_read_blobs(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let blob = &res.responses[0];
+        assert_eq!(blob.compressor, Compressor::Zstd as i32);
+        assert_eq!(zstd::decode_all(&blob.data[..]).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_digests() {
+        let store = MemStore::new();
+        let service = ContentAddressableStorageService::new(store.clone());
+        let hash = hash::sha256(b"foo").to_string();
+        let malformed = [
+            ("../../etc/passwd", 3),
+            (&hash.to_uppercase()[..], 3),
+            (&hash[1..], 3),
+            (&hash[..], -1),
+        ]
+        .map(|(hash, size_bytes)| Digest {
+            hash: hash.to_string(),
+            size_bytes,
+        });
+
+        for digest in malformed {
+            let req = FindMissingBlobsRequest {
+                blob_digests: vec![digest.clone()],
+                ..Default::default()
+            };
+            let err = service
+                .find_missing_blobs(Request::new(req))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument, "{digest:?}");
+
+            let err = batch_read(&service, vec![digest.clone()])
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+
+            let req = BatchUpdateBlobsRequest {
+                requests: vec![batch_update_blobs_request::Request {
+                    digest: Some(digest.clone()),
+                    data: b"foo".to_vec(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let res = service
+                .batch_update_blobs(Request::new(req))
+                .await
+                .unwrap()
+                .into_inner();
+            let status = res.responses[0].status.as_ref().unwrap();
+            assert_eq!(Code::from_i32(status.code), Code::InvalidArgument);
+
+            let err = get_tree(&service, &digest, 0, "").await.unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+        }
+
+        assert!(store.list().unwrap().next().is_none());
+    }
+
+    async fn get_tree(
+        service: &ContentAddressableStorageService<MemStore>,
+        root: &Digest,
+        page_size: i32,
+        page_token: &str,
+    ) -> Result<Vec<GetTreeResponse>, Status> {
+        let req = GetTreeRequest {
+            root_digest: Some(root.clone()),
+            page_size,
+            page_token: page_token.to_string(),
+            ..Default::default()
+        };
+
+        let stream = service.get_tree(Request::new(req)).await?.into_inner();
+        stream.collect::<Result<Vec<_>, _>>().await
+    }
+
+    #[tokio::test]
+    async fn test_get_tree_pages() {
+        let store = MemStore::new();
+        let service = ContentAddressableStorageService::new(store.clone());
+        let node = |name: &str, digest: &Digest| DirectoryNode {
+            name: name.to_string(),
+            digest: Some(digest.clone()),
+        };
+
+        // `c` is in both `a` and `b`, twice in `b`, but only returned once.
+        let file = put(&store, b"file");
+        let c = Directory {
+            files: vec![FileNode {
+                name: "file".to_string(),
+                digest: Some(file),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let c_digest = put(&store, &c.encode_to_vec());
+        let a = Directory {
+            directories: vec![node("c", &c_digest)],
+            ..Default::default()
+        };
+        let b = Directory {
+            directories: vec![node("c", &c_digest), node("d", &c_digest)],
+            ..Default::default()
+        };
+        let root = Directory {
+            directories: vec![
+                node("a", &put(&store, &a.encode_to_vec())),
+                node("b", &put(&store, &b.encode_to_vec())),
+            ],
+            ..Default::default()
+        };
+        let root_digest = put(&store, &root.encode_to_vec());
+
+        let pages = get_tree(&service, &root_digest, 3, "").await.unwrap();
+        let tokens = pages
+            .iter()
+            .map(|page| page.next_page_token.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["3", ""]);
+        let directories = pages
+            .into_iter()
+            .flat_map(|page| page.directories)
+            .collect::<Vec<_>>();
+        assert_eq!(directories, [root.clone(), a, b, c.clone()]);
+
+        // A request can be resumed from the token of the last page.
+        let pages = get_tree(&service, &root_digest, 3, "3").await.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].directories, [c]);
+
+        let pages = get_tree(&service, &root_digest, 0, "").await.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].directories.len(), 4);
+
+        let err = get_tree(&service, &root_digest, 3, "x").await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let missing = Digest {
+            hash: hash::sha256(b"missing").to_string(),
+            size_bytes: 7,
+        };
+        let err = get_tree(&service, &missing, 3, "").await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[test]
+    fn test_read_blob_size() {
+        let storage = MemStore::new();
+        let cas = CasInstance {
+            storage: storage.clone(),
+        };
+        let digest = put(&storage, b"foobar");
+        assert_eq!(cas.read_blob(0, &digest).unwrap(), b"foobar");
+
+        for size_bytes in [3, 7] {
+            let digest = Digest {
+                size_bytes,
+                ..digest.clone()
+            };
+            let err = cas.read_blob(0, &digest).unwrap_err();
+            assert!(matches!(err, Error::InvalidArgument(_)));
+        }
     }
 }
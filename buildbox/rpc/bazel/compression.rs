@@ -0,0 +1,77 @@
+//! Compression of blobs in transit. Clients opt in either through
+//! `compressed-blobs` resource names in the ByteStream API, or through the
+//! compressor fields of the batch CAS APIs. Blobs are always stored and
+//! verified uncompressed.
+
+use common::Error;
+pub(crate) use proto::bazel::exec::compressor::Value as Compressor;
+use std::borrow::Cow;
+use std::io::Read;
+
+/// Compressors that clients may use, besides `IDENTITY` which is always
+/// supported.
+pub(crate) const SUPPORTED_COMPRESSORS: [Compressor; 1] = [Compressor::Zstd];
+
+/// Level used when compressing blobs for clients. Low levels are plenty for
+/// typical build outputs and keep the server from becoming CPU bound.
+pub(crate) const ZSTD_LEVEL: i32 = 1;
+
+/// Parse the compressor segment of a `compressed-blobs` resource name.
+pub(crate) fn parse(name: &str) -> Result<Compressor, Error> {
+    match Compressor::from_str_name(&name.to_uppercase()) {
+        Some(compressor) if compressor == Compressor::Identity || is_supported(compressor) => {
+            Ok(compressor)
+        }
+        _ => Err(Error::invalid(&format!("unsupported compressor: {name}"))),
+    }
+}
+
+/// Check the compressor of a batch request, which is sent as a raw enum value.
+pub(crate) fn from_i32(value: i32) -> Result<Compressor, Error> {
+    match Compressor::try_from(value) {
+        Ok(compressor) if compressor == Compressor::Identity || is_supported(compressor) => {
+            Ok(compressor)
+        }
+        _ => Err(Error::invalid(&format!("unsupported compressor: {value}"))),
+    }
+}
+
+fn is_supported(compressor: Compressor) -> bool {
+    SUPPORTED_COMPRESSORS.contains(&compressor)
+}
+
+/// Compress a whole blob.
+pub(crate) fn compress(compressor: Compressor, data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    match compressor {
+        Compressor::Identity => Ok(Cow::Borrowed(data)),
+        Compressor::Zstd => zstd::encode_all(data, ZSTD_LEVEL)
+            .map(Cow::Owned)
+            .map_err(Error::io),
+        _ => Err(Error::invalid("unsupported compressor")),
+    }
+}
+
+/// Decompress a whole blob that is expected to be `size` bytes once
+/// decompressed. Decompression stops shortly after that, so that a small
+/// malicious payload can't expand into an arbitrary amount of memory.
+pub(crate) fn decompress(
+    compressor: Compressor,
+    data: &[u8],
+    size: u64,
+) -> Result<Cow<'_, [u8]>, Error> {
+    match compressor {
+        Compressor::Identity => Ok(Cow::Borrowed(data)),
+        Compressor::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(data).map_err(Error::io)?;
+
+            let mut buf = vec![];
+            decoder
+                .take(size.saturating_add(1))
+                .read_to_end(&mut buf)
+                .map_err(|err| Error::invalid(&format!("invalid zstd data: {err}")))?;
+
+            Ok(Cow::Owned(buf))
+        }
+        _ => Err(Error::invalid("unsupported compressor")),
+    }
+}
@@ -1,5 +1,11 @@
-use super::ResponseStream;
+use super::tree::DirectoryWalker;
+use super::action_cache::ActionCacheInstance;
+use super::instance::Instances;
+use super::{
+    blocking, into_rpc_status, into_status, required_digest, ActionCacheService, ResponseStream,
+};
 use bytes::BytesMut;
+use common::hash::{BlobDigest, DigestFunction};
 use common::Error;
 use executor::{
     DentryTemplate, DirTemplate, ExecCommand, Executor, SandboxHandle, FileTemplate, SandboxTemplate,
@@ -7,63 +13,161 @@ use executor::{
 };
 use prost::Message;
 use proto::bazel::exec::{
-    Action, ActionResult, Command, Digest, Directory, DirectoryNode, ExecuteRequest,
-    ExecuteResponse, Execution, FileNode, OutputFile, SymlinkNode, WaitExecutionRequest,
+    Action, ActionResult, Command, Digest, Directory, ExecuteRequest, ExecuteResponse, Execution,
+    OutputFile, WaitExecutionRequest,
 };
 use proto::google::{
     longrunning::{operation, Operation},
     protobuf::Any,
     rpc,
 };
-use std::collections::HashMap;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
 use storage::{Store, ProtoStoreExt};
 
+/// Why the input tree of an action couldn't be turned into a sandbox.
 #[derive(Debug)]
-pub struct ExecutionService<S, E> {
-    store: S,
-    executor: E,
+enum TemplateError {
+    /// The tree breaks the rules of the protocol. This is reported in the
+    /// [`ExecuteResponse`] rather than failing the request.
+    Invalid(rpc::Status),
+    Other(Error),
 }
 
-impl<S, E> ExecutionService<S, E>
+impl From<Error> for TemplateError {
+    fn from(err: Error) -> Self {
+        TemplateError::Other(err)
+    }
+}
+
+/// Runs actions in sandboxes created by an [`Executor`]. Every instance has
+/// an executor of its own, and results are cached in the action cache of the
+/// instance.
+#[derive(Debug, Clone)]
+pub struct ExecutionService<S, E, A = S>
+where
+    S: Store + 'static,
+    A: Store + 'static,
+{
+    action_cache: ActionCacheService<S, A>,
+    /// The store that inputs are read from and the executor of each instance.
+    instances: Instances<(S, E)>,
+}
+
+impl<S, E, A> ExecutionService<S, E, A>
 where
     S: Store + 'static,
     E: Executor + 'static,
+    A: Store + 'static,
 {
     /// Create new [`ExecutionService`] instance.
     #[must_use]
-    pub fn new(store: S, executor: E) -> Self {
-        Self { store, executor }
+    pub fn new(store: S, action_cache: ActionCacheService<S, A>, executor: E) -> Self {
+        Self {
+            action_cache,
+            instances: Instances::new((store, executor)),
+        }
     }
 
-    async fn execute(&self, req: &ExecuteRequest) -> Result<ExecuteResponse, Error> {
-        let action = req
-            .action_digest
-            .as_ref()
-            .ok_or_else(|| Error::invalid("missing action digest"))
-            .and_then(|digest| self.store.read_message::<Action>(&digest))?;
+    /// Run the actions of the instance called `name` with `executor`, reading
+    /// their inputs from `store`. The instance must also have been added to
+    /// the action cache.
+    #[must_use]
+    pub fn with_instance(mut self, name: &str, store: S, executor: E) -> Self {
+        self.instances.insert(name, (store, executor));
+        self
+    }
+
+    fn instance(&self, name: &str) -> Result<ExecutionInstance<S, E, A>, Error> {
+        let (store, executor) = self.instances.get(name)?.clone();
+
+        Ok(ExecutionInstance {
+            store,
+            action_cache: self.action_cache.instance(name)?,
+            executor,
+        })
+    }
+}
+
+/// Everything needed to run the actions of a single instance.
+struct ExecutionInstance<S, E, A> {
+    store: S,
+    action_cache: ActionCacheInstance<S, A>,
+    executor: E,
+}
+
+impl<S, E, A> ExecutionInstance<S, E, A>
+where
+    S: Store + 'static,
+    E: Executor + 'static,
+    A: Store + 'static,
+{
+    /// Run an action, or find its result in the action cache. This blocks
+    /// until the action has finished.
+    fn execute(&self, req: &ExecuteRequest) -> Result<ExecuteResponse, Error> {
+        let action_digest = required_digest(
+            req.digest_function,
+            req.action_digest.as_ref(),
+            "action digest",
+        )?;
+        let function = action_digest.function();
+
+        if !req.skip_cache_lookup {
+            if let Some(result) = self.action_cache.lookup(&action_digest)? {
+                tracing::info!("Action cache hit for {}", action_digest.hash());
+                return Ok(self.response(result, true));
+            }
+        }
+
+        let action = self
+            .store
+            .read_message::<Action>(function, &action_digest.clone().into())?;
 
         let input_root = action
             .input_root_digest
             .as_ref()
-            .ok_or_else(|| Error::invalid("missing input root"))
-            .and_then(|digest| self.store.read_message::<Directory>(&digest))?;
+            .ok_or_else(|| Error::invalid("missing input root"))?;
 
         let command = action
             .command_digest
             .as_ref()
             .ok_or_else(|| Error::invalid("missing command digest"))
-            .and_then(|digest| self.store.read_message::<Command>(&digest))?;
+            .and_then(|digest| self.store.read_message::<Command>(function, digest))?;
 
         tracing::info!("command: {command:?}");
 
-        let template = self.build_sandbox_template(&input_root)?;
+        let template = match self.build_sandbox_template(function, input_root) {
+            Ok(template) => template,
+            Err(TemplateError::Invalid(status)) => {
+                tracing::info!("Invalid input tree: {}", status.message);
+                return Ok(ExecuteResponse {
+                    result: None,
+                    cached_result: false,
+                    status: Some(status),
+                    server_logs: HashMap::new(),
+                    message: String::new(),
+                });
+            }
+            Err(TemplateError::Other(err)) => return Err(err),
+        };
+
+        // Keep the inputs from being evicted until the result is cached. The
+        // outputs are leased by the sandbox as they're stored, so the sandbox
+        // is kept around until then too.
+        let mut names = vec![];
+        for dentry in &template.filesystem {
+            if let DentryTemplate::File(file) = dentry {
+                names.push(BlobDigest::from_proto(function, &file.digest)?.blob_name());
+            }
+        }
+        let inputs = names.iter().map(String::as_str).collect::<Vec<_>>();
+        let lease = self.store.lease(&inputs);
+
         let mut sandbox = self.executor.spawn(&template)?;
         sandbox.prepare()?;
 
@@ -106,38 +210,47 @@ where
             execution_metadata: None,
         };
 
+        // Only successful results are cached, so that a flaky failure doesn't
+        // get replayed to every later build.
+        if action_res.exit_code == 0 && !action.do_not_cache {
+            if let Err(err) = self.action_cache.update(&action_digest, &action_res) {
+                tracing::warn!("Failed to cache result for {}: {err}", action_digest.hash());
+            }
+        }
+
+        drop((lease, sandbox));
+        Ok(self.response(action_res, false))
+    }
+
+    fn response(&self, result: ActionResult, cached_result: bool) -> ExecuteResponse {
         let status = rpc::Status {
             code: 0,
             message: "succcess".to_string(),
             details: vec![],
         };
 
-        let res = ExecuteResponse {
-            result: Some(action_res),
-            cached_result: false,
+        ExecuteResponse {
+            result: Some(result),
+            cached_result,
             status: Some(status),
             server_logs: HashMap::new(),
             message: "exec response".to_string(),
-        };
-
-        Ok(res)
+        }
     }
 
-    fn build_sandbox_template(&self, input_root: &Directory) -> Result<SandboxTemplate, Error> {
-        struct DirEntry {
-            path: PathBuf,
-            dir: Directory,
-        };
-
-        let mut next = VecDeque::new();
+    fn build_sandbox_template(
+        &self,
+        function: DigestFunction,
+        input_root: &Digest,
+    ) -> Result<SandboxTemplate, TemplateError> {
         let mut actions = vec![];
+        let absolute_symlinks = self.executor.allows_absolute_symlinks();
 
-        next.push_back(DirEntry {
-            path: PathBuf::new(),
-            dir: input_root.clone(),
-        });
+        for entry in DirectoryWalker::new(&self.store, function, input_root) {
+            let entry = entry?;
+            check_directory(&entry.path, &entry.digest, &entry.dir, absolute_symlinks)
+                .map_err(TemplateError::Invalid)?;
 
-        while let Some(entry) = next.pop_front() {
             actions.push(DentryTemplate::Dir(DirTemplate {
                 path: entry.path.clone(),
             }));
@@ -158,25 +271,13 @@ where
             for symlink in &entry.dir.symlinks {
                 actions.push(DentryTemplate::Symlink(SymlinkTemplate {
                     path: self.relative_path(&entry.path, &symlink.name),
-                    target: self.relative_path(&entry.path, &symlink.target),
+                    target: PathBuf::from(&symlink.target),
                 }));
             }
-
-            for dir_node in &entry.dir.directories {
-                let dir = dir_node
-                    .digest
-                    .as_ref()
-                    .ok_or_else(|| Error::invalid("missing directory"))
-                    .and_then(|digest| self.store.read_message::<Directory>(&digest))?;
-
-                next.push_back(DirEntry {
-                    path: self.relative_path(&entry.path, &dir_node.name),
-                    dir,
-                });
-            }
         }
 
         Ok(SandboxTemplate {
+            digest_function: function,
             filesystem: actions,
         })
     }
@@ -188,11 +289,113 @@ where
     }
 }
 
+/// Check that a directory of the input tree follows the rules of the protocol,
+/// so that every node in it ends up inside of the sandbox. Each kind of node
+/// must be sorted by name, and no name may be used twice. Symlinks can only
+/// have absolute targets if `absolute_symlinks` is set.
+fn check_directory(
+    path: &Path,
+    digest: &Digest,
+    dir: &Directory,
+    absolute_symlinks: bool,
+) -> Result<(), rpc::Status> {
+    let invalid =
+        |msg: String| invalid_directory(digest, format!("invalid input directory {path:?}: {msg}"));
+
+    let mut seen = HashSet::new();
+    let files = dir.files.iter().map(|node| node.name.as_str());
+    check_names("files", files, &mut seen).map_err(&invalid)?;
+    let dirs = dir.directories.iter().map(|node| node.name.as_str());
+    check_names("directories", dirs, &mut seen).map_err(&invalid)?;
+    let symlinks = dir.symlinks.iter().map(|node| node.name.as_str());
+    check_names("symlinks", symlinks, &mut seen).map_err(&invalid)?;
+
+    for symlink in &dir.symlinks {
+        if symlink.target.is_empty() {
+            return Err(invalid(format!("symlink {:?} has no target", symlink.name)));
+        }
+
+        // The protocol asks for INVALID_ARGUMENT rather than a failed
+        // precondition when absolute targets are disallowed.
+        if symlink.target.starts_with('/') && !absolute_symlinks {
+            return Err(into_rpc_status(Error::invalid(&format!(
+                "symlink {:?} in {path:?} has an absolute target",
+                symlink.name
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// A FAILED_PRECONDITION status for a directory of the input tree that breaks
+/// the rules of the protocol, naming the directory in a `PreconditionFailure`.
+fn invalid_directory(digest: &Digest, message: String) -> rpc::Status {
+    let failure = rpc::PreconditionFailure {
+        violations: vec![rpc::precondition_failure::Violation {
+            r#type: "INVALID".to_string(),
+            subject: format!("blobs/{}/{}", digest.hash, digest.size_bytes),
+            description: message.clone(),
+        }],
+    };
+
+    let detail = Any {
+        type_url: "type.googleapis.com/google.rpc.PreconditionFailure".to_string(),
+        value: failure.encode_to_vec(),
+    };
+
+    rpc::Status {
+        code: Code::FailedPrecondition as i32,
+        message,
+        details: vec![detail],
+    }
+}
+
+/// Check the names of one kind of node, which must be sorted and can't be
+/// used by any other node of the directory.
+fn check_names<'a>(
+    kind: &str,
+    names: impl Iterator<Item = &'a str>,
+    seen: &mut HashSet<&'a str>,
+) -> Result<(), String> {
+    let mut last = None;
+
+    for name in names {
+        check_name(name)?;
+
+        if !seen.insert(name) {
+            return Err(format!("{name:?} is used more than once"));
+        }
+
+        if last.is_some_and(|last| last > name) {
+            return Err(format!("{kind} are not sorted by name"));
+        }
+
+        last = Some(name);
+    }
+
+    Ok(())
+}
+
+/// Check that a node name is a single path component.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("{name:?} is not a valid name"));
+    }
+
+    if name.contains('/') || name.contains('\0') {
+        return Err(format!("{name:?} is not a single path component"));
+    }
+
+    Ok(())
+}
+
 #[async_trait::async_trait]
-impl<S, E> Execution for ExecutionService<S, E>
+impl<S, E, A> Execution for ExecutionService<S, E, A>
 where
     S: Store + 'static,
     E: Executor + 'static,
+    A: Store + 'static,
 {
     type ExecuteStream = ReceiverStream<Result<Operation, Status>>;
 
@@ -205,10 +408,8 @@ where
 
         let (tx, rx) = mpsc::channel(1);
 
-        let res = self
-            .execute(&req)
-            .await
-            .map_err(|err| Status::internal("failed to execute"))?;
+        let instance = self.instance(&req.instance_name).map_err(into_status)?;
+        let res = blocking(move || instance.execute(&req)).await?;
 
         let any = prost_types::Any::from_msg(&res)
             .map_err(|err| Status::internal("failed to map to any type"))?;
@@ -242,3 +443,236 @@ where
         todo!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::rand;
+    use executor::LocalExecutor;
+    use proto::bazel::exec::{DirectoryNode, FileNode, SymlinkNode};
+    use storage::mem::MemStore;
+    use tokio_stream::StreamExt;
+
+    const FUNCTION: DigestFunction = DigestFunction::Sha256;
+
+    /// Store an action that runs `script` in `input_root`.
+    fn store_action(store: &MemStore, script: &str, input_root: &Directory) -> Digest {
+        let write = |message: &[u8]| store.write_digest(FUNCTION, message).unwrap();
+
+        let command = Command {
+            arguments: ["/bin/sh", "-c", script].map(String::from).to_vec(),
+            ..Default::default()
+        };
+        let action = Action {
+            command_digest: Some(write(&command.encode_to_vec())),
+            input_root_digest: Some(write(&input_root.encode_to_vec())),
+            ..Default::default()
+        };
+
+        write(&action.encode_to_vec())
+    }
+
+    #[test]
+    fn test_execute_caches_results() {
+        let dir = std::env::temp_dir().join(format!("rust-test-{}", rand::string(20)));
+        std::fs::create_dir(&dir).unwrap();
+
+        let (store, action_cache) = (MemStore::new(), MemStore::new());
+        let executor = LocalExecutor::new(dir.clone(), store.clone(), false);
+        let action_cache_service = ActionCacheService::new(store.clone(), action_cache.clone());
+        let service = ExecutionService::new(store.clone(), action_cache_service, executor);
+        let instance = service.instance("").unwrap();
+
+        let execute = |action_digest: &Digest, skip_cache_lookup| {
+            let req = ExecuteRequest {
+                action_digest: Some(action_digest.clone()),
+                skip_cache_lookup,
+                ..Default::default()
+            };
+            instance.execute(&req).unwrap()
+        };
+
+        let succeeds = store_action(&store, "echo hello", &Directory::default());
+        let res = execute(&succeeds, false);
+        assert!(!res.cached_result);
+        assert_eq!(res.result.as_ref().unwrap().exit_code, 0);
+
+        let cached = execute(&succeeds, false);
+        assert!(cached.cached_result);
+        assert_eq!(cached.result, res.result);
+        assert!(!execute(&succeeds, true).cached_result);
+
+        // Failures aren't cached, so they're run again.
+        let fails = store_action(&store, "exit 3", &Directory::default());
+        assert_eq!(execute(&fails, false).result.unwrap().exit_code, 3);
+        assert!(!execute(&fails, false).cached_result);
+        let name = BlobDigest::from_proto(FUNCTION, &fails)
+            .unwrap()
+            .blob_name();
+        assert!(!action_cache.contains(&name).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn service(store: &MemStore) -> ExecutionService<MemStore, LocalExecutor<MemStore>> {
+        // Sandboxes aren't spawned when the input tree is invalid.
+        let executor = LocalExecutor::new(std::env::temp_dir(), store.clone(), false);
+        let action_cache = ActionCacheService::new(store.clone(), MemStore::new());
+        ExecutionService::new(store.clone(), action_cache, executor)
+    }
+
+    fn file(name: &str) -> FileNode {
+        FileNode {
+            name: name.to_string(),
+            digest: Some(Digest::default()),
+            ..Default::default()
+        }
+    }
+
+    fn directory(name: &str, digest: &Digest) -> DirectoryNode {
+        DirectoryNode {
+            name: name.to_string(),
+            digest: Some(digest.clone()),
+        }
+    }
+
+    fn symlink(name: &str, target: &str) -> SymlinkNode {
+        SymlinkNode {
+            name: name.to_string(),
+            target: target.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_invalid_input_tree() {
+        let store = MemStore::new();
+        let instance = service(&store).instance("").unwrap();
+        let empty = store
+            .write_digest(FUNCTION, &Directory::default().encode_to_vec()[..])
+            .unwrap();
+
+        let invalid = [
+            Directory {
+                files: vec![file("b"), file("a")],
+                ..Default::default()
+            },
+            Directory {
+                files: vec![file("a")],
+                directories: vec![directory("a", &empty)],
+                ..Default::default()
+            },
+            Directory {
+                directories: vec![directory("a", &empty)],
+                symlinks: vec![symlink("a", "b")],
+                ..Default::default()
+            },
+            Directory {
+                files: vec![file("a")],
+                symlinks: vec![symlink("a", "b")],
+                ..Default::default()
+            },
+            Directory {
+                files: vec![file("..")],
+                ..Default::default()
+            },
+            Directory {
+                directories: vec![directory("a/b", &empty)],
+                ..Default::default()
+            },
+            Directory {
+                files: vec![file("")],
+                ..Default::default()
+            },
+            Directory {
+                symlinks: vec![symlink("a", "")],
+                ..Default::default()
+            },
+        ];
+
+        for dir in invalid {
+            let digest = store
+                .write_digest(FUNCTION, &dir.encode_to_vec()[..])
+                .unwrap();
+            let Err(TemplateError::Invalid(status)) =
+                instance.build_sandbox_template(FUNCTION, &digest)
+            else {
+                panic!("{dir:?} should be invalid");
+            };
+            assert_eq!(status.code, Code::FailedPrecondition as i32, "{dir:?}");
+
+            // The directory is named in the details of the status.
+            assert_eq!(status.details.len(), 1);
+            let detail = &status.details[0];
+            assert!(detail.type_url.ends_with("/google.rpc.PreconditionFailure"));
+            let failure = rpc::PreconditionFailure::decode(&detail.value[..]).unwrap();
+            let subject = format!("blobs/{}/{}", digest.hash, digest.size_bytes);
+            assert_eq!(failure.violations[0].subject, subject);
+        }
+
+        // Absolute targets are rejected as an invalid argument instead.
+        let dir = Directory {
+            symlinks: vec![symlink("a", "/etc/passwd")],
+            ..Default::default()
+        };
+        let digest = store
+            .write_digest(FUNCTION, &dir.encode_to_vec()[..])
+            .unwrap();
+        let Err(TemplateError::Invalid(status)) =
+            instance.build_sandbox_template(FUNCTION, &digest)
+        else {
+            panic!("absolute symlink targets should be invalid");
+        };
+        assert_eq!(status.code, Code::InvalidArgument as i32);
+
+        // They're fine when the executor can create them.
+        let executor = LocalExecutor::new(std::env::temp_dir(), store.clone(), false)
+            .with_absolute_symlinks(true);
+        let action_cache = ActionCacheService::new(store.clone(), MemStore::new());
+        let service = ExecutionService::new(store.clone(), action_cache, executor);
+        let allowed = service.instance("").unwrap();
+        assert!(allowed.build_sandbox_template(FUNCTION, &digest).is_ok());
+
+        let dir = Directory {
+            files: vec![file("a"), file("b")],
+            directories: vec![directory("c", &empty)],
+            symlinks: vec![symlink("d", "../a")],
+            ..Default::default()
+        };
+        let digest = store
+            .write_digest(FUNCTION, &dir.encode_to_vec()[..])
+            .unwrap();
+        assert!(instance.build_sandbox_template(FUNCTION, &digest).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_execute_invalid_input_tree() {
+        let store = MemStore::new();
+        let service = service(&store);
+        let input_root = Directory {
+            files: vec![file("b"), file("a")],
+            ..Default::default()
+        };
+        let action_digest = store_action(&store, "echo hello", &input_root);
+
+        // The action fails rather than the request, so the operation is
+        // completed with the status in its response.
+        let req = ExecuteRequest {
+            action_digest: Some(action_digest),
+            ..Default::default()
+        };
+        let mut stream = Execution::execute(&service, Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let op = stream.next().await.unwrap().unwrap();
+        assert!(op.done);
+
+        let Some(operation::Result::Response(any)) = op.result else {
+            panic!("operation should have a response: {op:?}");
+        };
+        let res = ExecuteResponse::decode(&any.value[..]).unwrap();
+        assert_eq!(res.status.unwrap().code, Code::FailedPrecondition as i32);
+        assert!(res.result.is_none());
+    }
+}
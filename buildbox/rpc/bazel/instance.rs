@@ -0,0 +1,90 @@
+//! Instance names, which let a single server keep several caches apart.
+
+use common::Error;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Name of the default instance, which is used by clients that don't set an
+/// instance name.
+pub(crate) const DEFAULT_INSTANCE_NAME: &str = "";
+
+/// Whatever a service keeps for each instance, such as the store that the
+/// instance is served from.
+///
+/// The default instance always exists. As long as it's the only one, every
+/// instance name refers to it, so that clients which set an instance name of
+/// their own keep working. Once other instances are added, requests for
+/// instances that don't exist are refused.
+#[derive(Debug)]
+pub(crate) struct Instances<T> {
+    instances: Arc<HashMap<String, T>>,
+}
+
+impl<T> Instances<T> {
+    /// Create the instances of a service, starting with the default one.
+    pub fn new(default: T) -> Self {
+        let mut instances = HashMap::new();
+        instances.insert(DEFAULT_INSTANCE_NAME.to_string(), default);
+
+        Self {
+            instances: Arc::new(instances),
+        }
+    }
+
+    /// Find the instance that `name` refers to.
+    pub fn get(&self, name: &str) -> Result<&T, Error> {
+        if let Some(instance) = self.instances.get(name) {
+            return Ok(instance);
+        }
+
+        match self.instances.len() {
+            1 => Ok(&self.instances[DEFAULT_INSTANCE_NAME]),
+            _ => Err(Error::invalid(&format!("unknown instance name: {name:?}"))),
+        }
+    }
+}
+
+impl<T: Clone> Instances<T> {
+    /// Add an instance called `name`, replacing any instance of that name.
+    pub fn insert(&mut self, name: &str, instance: T) {
+        Arc::make_mut(&mut self.instances).insert(name.to_string(), instance);
+    }
+}
+
+impl<T: Default> Default for Instances<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Clone for Instances<T> {
+    fn clone(&self) -> Self {
+        Self {
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_instances() {
+        let mut instances = Instances::new("default");
+        assert_eq!(*instances.get("").unwrap(), "default");
+        assert_eq!(*instances.get("main").unwrap(), "default");
+
+        instances.insert("main", "main");
+        instances.insert("experimental/toolchains", "experimental");
+        assert_eq!(*instances.get("").unwrap(), "default");
+        assert_eq!(*instances.get("main").unwrap(), "main");
+        assert_eq!(
+            *instances.get("experimental/toolchains").unwrap(),
+            "experimental"
+        );
+
+        let err = instances.get("other").unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+    }
+}
@@ -21,6 +21,89 @@ pub use fetch::FetchService;
 pub mod push;
 pub use push::PushService;
 
+pub(crate) mod compression;
+pub(crate) mod instance;
+pub(crate) mod tree;
+
+use common::hash::{BlobDigest, DigestFunction};
+use common::Error;
+use proto::bazel::exec::{symlink_absolute_path_strategy, Digest};
+use proto::google::rpc;
 use std::pin::Pin;
 use tokio_stream::Stream;
-pub type ResponseStream<T> = Pin<Box<dyn Stream<Item = T> + Send + 'static>>;
\ No newline at end of file
+use tonic::Status;
+
+pub type ResponseStream<T> = Pin<Box<dyn Stream<Item = T> + Send + 'static>>;
+
+/// Upper bound on the total size of the blobs in a single batch request or
+/// response. This is kept under tonic's default 4 MiB message limit to leave
+/// room for the digests and statuses that accompany the data.
+pub(crate) const MAX_BATCH_TOTAL_SIZE_BYTES: i64 = 4 * 1024 * 1024 - 64 * 1024;
+
+/// How input symlinks with absolute targets are treated. They point outside
+/// of the sandbox, so they're only allowed if the executor creates them.
+pub(crate) fn absolute_symlink_strategy(allowed: bool) -> symlink_absolute_path_strategy::Value {
+    if allowed {
+        symlink_absolute_path_strategy::Value::Allowed
+    } else {
+        symlink_absolute_path_strategy::Value::Disallowed
+    }
+}
+
+/// Run work that blocks on storage or the executor on tokio's blocking thread
+/// pool, so that a slow disk or a long action doesn't hold up unrelated RPCs
+/// scheduled on the same worker thread.
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Status::internal(format!("blocking task failed: {err}")))?
+        .map_err(into_status)
+}
+
+/// Check a digest sent by a client before it's used to find a blob, so that a
+/// malformed hash is rejected rather than passed on to the store.
+///
+/// `digest_function` is the field of the request that the digest came in.
+/// Clients that don't set it are assumed to use the function that matches the
+/// length of the hash.
+pub(crate) fn blob_digest(digest_function: i32, digest: &Digest) -> Result<BlobDigest, Error> {
+    let function = DigestFunction::from_request(digest_function, &digest.hash)?;
+    BlobDigest::from_proto(function, digest)
+}
+
+/// Check a digest that a request must include, which is called `name` in
+/// errors.
+pub(crate) fn required_digest(
+    digest_function: i32,
+    digest: Option<&Digest>,
+    name: &str,
+) -> Result<BlobDigest, Error> {
+    let digest = digest.ok_or_else(|| Error::invalid(&format!("missing {name}")))?;
+    blob_digest(digest_function, digest)
+}
+
+/// Map an internal [`Error`] onto the closest matching gRPC [`Status`].
+pub(crate) fn into_status(err: Error) -> Status {
+    match err {
+        Error::NotFound(msg) => Status::not_found(msg),
+        Error::InvalidArgument(msg) => Status::invalid_argument(msg),
+        Error::FailedPrecondition(msg) => Status::failed_precondition(msg),
+        Error::PermissionDenied(msg) => Status::permission_denied(msg),
+        err => Status::internal(err.to_string()),
+    }
+}
+
+/// Map an internal [`Error`] onto the `google.rpc.Status` reported for a single
+/// item of a batch request. Successful items use the default (OK) status.
+pub(crate) fn into_rpc_status(err: Error) -> rpc::Status {
+    let status = into_status(err);
+    rpc::Status {
+        code: status.code() as i32,
+        message: status.message().to_string(),
+        details: vec![],
+    }
+}
@@ -0,0 +1,88 @@
+//! Traversal of the [`Directory`] graph stored in the CAS.
+
+use common::hash::DigestFunction;
+use common::{Error, Result};
+use proto::bazel::exec::{Digest, Directory};
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use storage::{ProtoStoreExt, Store};
+
+/// A directory reached while walking a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalkEntry {
+    /// Path of the directory relative to the root of the tree.
+    pub path: PathBuf,
+    /// Digest of the serialized [`Directory`].
+    pub digest: Digest,
+    /// The directory itself.
+    pub dir: Directory,
+}
+
+/// Iterates over every directory in a tree breadth-first, starting with the
+/// root. Each directory is read from the store as it is reached.
+pub struct DirectoryWalker<'a, S> {
+    store: &'a S,
+    function: DigestFunction,
+    next: VecDeque<(PathBuf, Digest)>,
+    seen: Option<HashSet<String>>,
+}
+
+impl<'a, S: Store> DirectoryWalker<'a, S> {
+    /// Create a new [`DirectoryWalker`] for the tree rooted at `root`, whose
+    /// digests are all made with `function`.
+    pub fn new(store: &'a S, function: DigestFunction, root: &Digest) -> Self {
+        let mut next = VecDeque::new();
+        next.push_back((PathBuf::new(), root.clone()));
+
+        Self {
+            store,
+            function,
+            next,
+            seen: None,
+        }
+    }
+
+    /// Only visit each distinct directory once, even if it appears at several
+    /// paths in the tree.
+    pub fn unique(mut self) -> Self {
+        let mut seen = HashSet::new();
+        for (_, digest) in &self.next {
+            seen.insert(digest.hash.clone());
+        }
+
+        self.seen = Some(seen);
+        self
+    }
+
+    fn visit(&mut self, path: PathBuf, digest: Digest) -> Result<WalkEntry> {
+        let dir = self
+            .store
+            .read_message::<Directory>(self.function, &digest)?;
+
+        for node in &dir.directories {
+            let child = node
+                .digest
+                .as_ref()
+                .ok_or_else(|| Error::invalid("missing directory"))?;
+
+            if let Some(seen) = &mut self.seen {
+                if !seen.insert(child.hash.clone()) {
+                    continue;
+                }
+            }
+
+            self.next.push_back((path.join(&node.name), child.clone()));
+        }
+
+        Ok(WalkEntry { path, digest, dir })
+    }
+}
+
+impl<S: Store> Iterator for DirectoryWalker<'_, S> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, digest) = self.next.pop_front()?;
+        Some(self.visit(path, digest))
+    }
+}
@@ -1,11 +1,17 @@
+use crate::bazel::into_status;
 use common::Error;
 use executor::{Executor, SandboxHandle};
 use proto::buildbox::{
     Buildbox, FindBlobsRequest, FindBlobsResponse, FindSandboxesRequest, FindSandboxesResponse,
 };
 use storage::Store;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
+/// Number of blob names sent in each `FindBlobs` response.
+const FIND_BLOBS_BATCH_SIZE: usize = 1000;
+
 #[derive(Debug)]
 pub struct BuildboxService<S, E>
 where
@@ -32,13 +38,59 @@ where
     S: Store + 'static,
     E: Executor + 'static,
 {
+    type FindBlobsStream = ReceiverStream<Result<FindBlobsResponse, Status>>;
+
+    /// List every blob in the store, in batches so that the listing never
+    /// has to be held in memory all at once.
     async fn find_blobs(
         &self,
         req: Request<FindBlobsRequest>,
-    ) -> Result<Response<FindBlobsResponse>, Status> {
+    ) -> Result<Response<Self::FindBlobsStream>, Status> {
         tracing::info!("BuildboxService::find_blobs");
-        let blobs = vec![];
-        Ok(Response::new(FindBlobsResponse { blobs }))
+
+        let storage = self.storage.clone();
+        let (tx, rx) = mpsc::channel(1);
+
+        // Listing walks the whole store, so it's kept off the async runtime.
+        tokio::task::spawn_blocking(move || {
+            let blobs = match storage.list() {
+                Ok(blobs) => blobs,
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(into_status(err)));
+                    return;
+                }
+            };
+
+            let mut batch = vec![];
+
+            for blob in blobs {
+                match blob {
+                    Ok(blob) => batch.push(blob.name),
+                    Err(err) => {
+                        let _ = tx.blocking_send(Err(into_status(err)));
+                        return;
+                    }
+                }
+
+                if batch.len() < FIND_BLOBS_BATCH_SIZE {
+                    continue;
+                }
+
+                let res = FindBlobsResponse {
+                    blobs: std::mem::take(&mut batch),
+                };
+
+                if tx.blocking_send(Ok(res)).is_err() {
+                    return;
+                }
+            }
+
+            if !batch.is_empty() {
+                let _ = tx.blocking_send(Ok(FindBlobsResponse { blobs: batch }));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn find_sandboxes(
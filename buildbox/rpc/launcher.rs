@@ -1,7 +1,7 @@
 use super::{bazel, buildbox};
-use common::config::Config;
+use common::config::{Config, StorageBackend};
 use common::{Error, Result};
-use executor::LocalExecutor;
+use executor::{Executor, LocalExecutor};
 use proto::bazel::asset::{FetchServer, PushServer};
 use proto::bazel::exec::ActionCacheServer;
 use proto::bazel::exec::CapabilitiesServer;
@@ -9,9 +9,24 @@ use proto::bazel::exec::ContentAddressableStorageServer;
 use proto::bazel::exec::ExecutionServer;
 use proto::buildbox::BuildboxServer;
 use proto::google::bytestream::ByteStreamServer;
-use storage::file::FileStore;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use storage::chunked::ChunkedStore;
+use storage::file::{FileStore, GcPolicy};
+use storage::mem::MemStore;
+use storage::tiered::TieredStore;
+use storage::verify::VerifyingStore;
+use storage::Store;
 use tonic::transport::Server;
 
+/// Subdirectory of the storage directory that holds the action cache.
+const ACTION_CACHE_DIR_NAME: &str = "action-cache";
+
+/// Subdirectory of the storage directory that holds the lists of chunks that
+/// chunked blobs are made of.
+const CHUNK_MANIFESTS_DIR_NAME: &str = "chunk-manifests";
+
 pub async fn launch(config: &Config) -> Result<()> {
     let addr = config
         .addr
@@ -19,21 +34,267 @@ pub async fn launch(config: &Config) -> Result<()> {
         .map_err(Error::boxed_msg("invalid address"))?;
     tracing::info!("Starting server on {addr}");
 
-    let storage = FileStore::new(config.storage_dir.clone().into());
+    match config.storage_backend {
+        StorageBackend::File => launch_with_files(config, addr).await,
+        StorageBackend::Memory => {
+            tracing::warn!("Blobs are only kept in memory, and are lost when the server stops");
+            let mut stores = Stores::new(MemStore::new(), MemStore::new(), MemStore::new());
+            for (name, instance) in &config.instances {
+                let storage = instance
+                    .separate_storage
+                    .then(|| (MemStore::new(), MemStore::new()));
+                stores.add_instance(name, MemStore::new(), storage);
+            }
 
-    let executor = LocalExecutor::new(
-        config.sandbox_dir.clone().into(),
-        storage.clone(),
-        config.retain_sandboxes,
+            serve_with_chunking(config, addr, stores).await
+        }
+    }
+}
+
+/// Serve blobs stored in the storage directory. Every instance keeps its
+/// stores in a directory of its own within it.
+async fn launch_with_files(config: &Config, addr: SocketAddr) -> Result<()> {
+    let storage_dir = PathBuf::from(&config.storage_dir);
+    let mut stores = Stores::new(
+        open_file_store(config, storage_dir.clone())?,
+        FileStore::open(storage_dir.join(CHUNK_MANIFESTS_DIR_NAME))?.with_sync(config.sync_writes),
+        // Action results are keyed by action digest rather than by their
+        // content, so they're kept apart from the CAS blobs.
+        open_file_store(config, storage_dir.join(ACTION_CACHE_DIR_NAME))?,
     );
 
+    for name in config.instances.keys() {
+        let instance_dir = config.instance_dir(name);
+        let action_cache = open_file_store(config, instance_dir.join(ACTION_CACHE_DIR_NAME))?;
+        let storage = match config.instance_storage_dir(name) {
+            Some(dir) => Some((
+                open_file_store(config, dir)?,
+                FileStore::open(instance_dir.join(CHUNK_MANIFESTS_DIR_NAME))?
+                    .with_sync(config.sync_writes),
+            )),
+            None => None,
+        };
+
+        stores.add_instance(name, action_cache, storage);
+    }
+
+    // Nothing is writing to the stores yet, so any temporary files were left
+    // behind by uploads that can't be finished anymore.
+    let action_caches = stores
+        .instances
+        .iter()
+        .map(|instance| &instance.action_cache);
+    for store in stores
+        .storage
+        .iter()
+        .chain(&stores.manifests)
+        .chain([&stores.action_cache])
+        .chain(action_caches)
+    {
+        store.remove_temp_files()?;
+    }
+
+    let gc_policy = GcPolicy {
+        max_size_bytes: config.max_storage_bytes,
+        min_free_bytes: config.min_free_disk_bytes,
+    };
+
+    if gc_policy != GcPolicy::default() {
+        let interval = Duration::from_secs(config.gc_interval_secs);
+        for storage in &stores.storage {
+            tokio::spawn(collect_garbage(storage.clone(), gc_policy, interval));
+        }
+    }
+
+    serve_with_chunking(config, addr, stores).await
+}
+
+fn open_file_store(config: &Config, dir: PathBuf) -> Result<FileStore> {
+    Ok(FileStore::open(dir)?
+        .with_compression(config.storage_compression)
+        .with_sync(config.sync_writes))
+}
+
+/// The stores that the server is made of. The default instance keeps its
+/// blobs in the first CAS, which is shared by every instance that doesn't
+/// have storage of its own. Every CAS has its own store of chunk manifests.
+struct Stores<S, M, A> {
+    storage: Vec<S>,
+    manifests: Vec<M>,
+    /// Action cache of the default instance.
+    action_cache: A,
+    instances: Vec<InstanceStores<A>>,
+}
+
+/// The action cache of a configured instance, and which CAS it keeps its
+/// blobs in.
+struct InstanceStores<A> {
+    name: String,
+    storage: usize,
+    action_cache: A,
+}
+
+impl<S, M, A> Stores<S, M, A> {
+    /// Start with the stores of the default instance.
+    fn new(storage: S, manifests: M, action_cache: A) -> Self {
+        Self {
+            storage: vec![storage],
+            manifests: vec![manifests],
+            action_cache,
+            instances: vec![],
+        }
+    }
+
+    /// Add the instance called `name`, which shares the CAS of the default
+    /// instance unless it comes with `storage` and manifests of its own.
+    fn add_instance(&mut self, name: &str, action_cache: A, storage: Option<(S, M)>) {
+        let index = match storage {
+            Some((storage, manifests)) => {
+                self.storage.push(storage);
+                self.manifests.push(manifests);
+                self.storage.len() - 1
+            }
+            None => 0,
+        };
+
+        self.instances.push(InstanceStores {
+            name: name.to_string(),
+            storage: index,
+            action_cache,
+        });
+    }
+
+    /// Wrap every CAS in another store.
+    fn map_storage<T>(self, f: impl FnMut(S) -> T) -> Stores<T, M, A> {
+        Stores {
+            storage: self.storage.into_iter().map(f).collect(),
+            manifests: self.manifests,
+            action_cache: self.action_cache,
+            instances: self.instances,
+        }
+    }
+}
+
+/// Split large blobs into chunks that are shared between blobs of the same
+/// CAS, if configured. The lists of chunks are kept in the manifests.
+async fn serve_with_chunking<S, M, A>(
+    config: &Config,
+    addr: SocketAddr,
+    mut stores: Stores<S, M, A>,
+) -> Result<()>
+where
+    S: Store + 'static,
+    M: Store + 'static,
+    A: Store + 'static,
+{
+    if config.storage_chunking {
+        let mut manifests = std::mem::take(&mut stores.manifests).into_iter();
+        let stores = stores.map_storage(|storage| {
+            let manifests = manifests.next().expect("every CAS has manifests");
+            ChunkedStore::new(storage, manifests)
+        });
+        serve_with_cache(config, addr, stores).await
+    } else {
+        serve_with_cache(config, addr, stores).await
+    }
+}
+
+/// Put a cache of small blobs in memory in front of every CAS, if configured.
+async fn serve_with_cache<S, M, A>(
+    config: &Config,
+    addr: SocketAddr,
+    stores: Stores<S, M, A>,
+) -> Result<()>
+where
+    S: Store + 'static,
+    A: Store + 'static,
+{
+    match config.memory_cache_bytes {
+        Some(max_size_bytes) => {
+            let stores = stores.map_storage(|storage| {
+                let cache = MemStore::new().with_max_size(max_size_bytes);
+                TieredStore::new(cache, storage)
+                    .with_max_cached_blob_size(config.memory_cache_max_blob_bytes)
+            });
+            serve_with_verification(config, addr, stores).await
+        }
+        None => serve_with_verification(config, addr, stores).await,
+    }
+}
+
+/// Check blobs as they're read from the CAS, if configured. Action results
+/// aren't named after their content, so only the CAS can be verified.
+async fn serve_with_verification<S, M, A>(
+    config: &Config,
+    addr: SocketAddr,
+    stores: Stores<S, M, A>,
+) -> Result<()>
+where
+    S: Store + 'static,
+    A: Store + 'static,
+{
+    if config.verify_reads {
+        serve(config, addr, stores.map_storage(VerifyingStore::new)).await
+    } else {
+        serve(config, addr, stores).await
+    }
+}
+
+/// Serve every service from the stores, with each instance registered with
+/// every service that takes an instance name.
+async fn serve<S, M, A>(config: &Config, addr: SocketAddr, stores: Stores<S, M, A>) -> Result<()>
+where
+    S: Store + 'static,
+    A: Store + 'static,
+{
+    let storage = stores.storage[0].clone();
+    let executor = local_executor(config, &config.sandbox_dir, storage.clone());
+
     let fetch_service = bazel::FetchService::default();
     let push_service = bazel::PushService::default();
-    let execution_service = bazel::ExecutionService::new(storage.clone(), executor.clone());
-    let action_cache_service = bazel::ActionCacheService::new(storage.clone());
-    let cas_service = bazel::ContentAddressableStorageService::new(storage.clone());
-    let bytestream_service = bazel::ByteStreamService::new(storage.clone());
-    let capabilities_service = bazel::CapabilitiesService::default();
+    let mut action_cache_service =
+        bazel::ActionCacheService::new(storage.clone(), stores.action_cache);
+    let mut cas_service = bazel::ContentAddressableStorageService::new(storage.clone());
+    let mut bytestream_service = bazel::ByteStreamService::new(
+        storage.clone(),
+        Duration::from_secs(config.upload_timeout_secs),
+        config.read_chunk_size_bytes,
+    );
+    let mut capabilities_service = bazel::CapabilitiesService::default()
+        .with_absolute_symlinks(executor.allows_absolute_symlinks());
+
+    let mut executors = vec![];
+    for instance in stores.instances {
+        let settings = &config.instances[&instance.name];
+        let storage = stores.storage[instance.storage].clone();
+        let sandbox_dir = settings.sandbox_dir.as_ref().unwrap_or(&config.sandbox_dir);
+        tracing::info!("Serving instance {}", instance.name);
+
+        action_cache_service = action_cache_service.with_instance(
+            &instance.name,
+            storage.clone(),
+            instance.action_cache,
+            settings.read_only,
+        );
+        cas_service = cas_service.with_instance(&instance.name, storage.clone());
+        bytestream_service = bytestream_service.with_instance(&instance.name, storage.clone());
+        capabilities_service =
+            capabilities_service.with_instance(&instance.name, settings.read_only);
+
+        let executor = local_executor(config, sandbox_dir, storage.clone());
+        executors.push((instance.name, storage, executor));
+    }
+
+    // The execution service caches results in the action cache of each
+    // instance, so it's only made once they've all been added.
+    let mut execution_service = bazel::ExecutionService::new(
+        storage.clone(),
+        action_cache_service.clone(),
+        executor.clone(),
+    );
+    for (name, storage, executor) in executors {
+        execution_service = execution_service.with_instance(&name, storage, executor);
+    }
 
     let buildbox_service = buildbox::BuildboxService::new(storage.clone(), executor.clone());
 
@@ -54,3 +315,32 @@ pub async fn launch(config: &Config) -> Result<()> {
 
     Ok(())
 }
+
+/// Create an executor that runs actions in `sandbox_dir`, with inputs taken
+/// from `storage`.
+fn local_executor<S: Store>(config: &Config, sandbox_dir: &str, storage: S) -> LocalExecutor<S> {
+    LocalExecutor::new(sandbox_dir.into(), storage, config.retain_sandboxes)
+        .with_absolute_symlinks(config.allow_absolute_symlinks)
+}
+
+/// Keep the store within the limits of `policy`, checking every `interval`.
+async fn collect_garbage(storage: FileStore, policy: GcPolicy, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let store = storage.clone();
+        match tokio::task::spawn_blocking(move || store.collect_garbage(&policy)).await {
+            Ok(Ok(report)) if report.evicted_blobs > 0 => tracing::info!(
+                "Evicted {} blobs, reclaiming {} bytes ({} bytes remaining)",
+                report.evicted_blobs,
+                report.reclaimed_bytes,
+                report.remaining_bytes
+            ),
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => tracing::error!("Failed to collect garbage: {err}"),
+            Err(err) => tracing::error!("Garbage collection panicked: {err}"),
+        }
+    }
+}
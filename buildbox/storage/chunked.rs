@@ -0,0 +1,590 @@
+use super::{BlobMetadata, Blobs, Lease, ProtoStoreExt, ReadHandle, Store, WriteHandle};
+use common::hash::{self, DigestFunction};
+use common::{Error, Result};
+use fastcdc::v2020::FastCDC;
+use proto::bazel::exec::{Digest, SplitBlobResponse};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Blobs smaller than this are stored whole by default. Splitting them would
+/// save little, and reading them back would take more work.
+pub const DEFAULT_MIN_CHUNKED_BLOB_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Bounds on the size of chunks. Chunks average 256 KiB, which keeps the
+/// manifest of a blob of several gigabytes down to tens of thousands of
+/// chunks while still sharing most of the data between similar blobs.
+const MIN_CHUNK_SIZE: u32 = 64 * 1024;
+const AVG_CHUNK_SIZE: u32 = 256 * 1024;
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// A [`Store`] that splits large blobs into content-defined chunks, so that
+/// blobs that only differ slightly share most of their chunks.
+///
+/// Each chunk is stored as a blob of its own in `store`, while the list of
+/// chunks that make up a blob, its manifest, is stored under the name of the
+/// blob in `manifests`. Small blobs are stored whole in `store`.
+///
+/// Chunks can be evicted like any other blob, so a blob only counts as stored
+/// while all of its chunks are. Manifests that refer to evicted chunks are
+/// removed once they're noticed.
+#[derive(Debug, Clone)]
+pub struct ChunkedStore<S, M = S> {
+    store: S,
+    manifests: M,
+    min_blob_size: u64,
+}
+
+impl<S: Store, M: Store> ChunkedStore<S, M> {
+    /// Create a new [`ChunkedStore`] that keeps chunks and small blobs in
+    /// `store`, and the manifests of chunked blobs in `manifests`.
+    pub fn new(store: S, manifests: M) -> Self {
+        Self {
+            store,
+            manifests,
+            min_blob_size: DEFAULT_MIN_CHUNKED_BLOB_BYTES,
+        }
+    }
+
+    /// Only split blobs of at least this size.
+    pub fn with_min_blob_size(mut self, min_blob_bytes: u64) -> Self {
+        self.min_blob_size = min_blob_bytes;
+        self
+    }
+
+    /// Read the manifest of a chunked blob, if there is one.
+    fn manifest(&self, name: &str) -> Result<Option<Manifest>> {
+        let mut reader = match self.manifests.read(name) {
+            Ok(reader) => reader,
+            Err(Error::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let metadata = reader.metadata()?;
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).map_err(Error::io)?;
+
+        let manifest = prost::Message::decode(buf.as_slice()).map_err(Error::boxed)?;
+        Ok(Some(Manifest::new(manifest, metadata)))
+    }
+}
+
+impl<S: Store, M: Store> Store for ChunkedStore<S, M> {
+    type ReadHandle = ChunkedReadHandle<S>;
+
+    type WriteHandle = ChunkedWriteHandle<S, M>;
+
+    fn read(&self, name: &str) -> Result<Self::ReadHandle> {
+        match self.store.read(name) {
+            Ok(reader) => return Ok(ChunkedReadHandle::Whole(reader)),
+            Err(Error::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        let manifest = self
+            .manifest(name)?
+            .ok_or_else(|| Error::not_found("file not found"))?;
+
+        Ok(ChunkedReadHandle::Chunked(ChunkReader::new(
+            self.store.clone(),
+            manifest,
+        )))
+    }
+
+    fn write(&self) -> Result<Self::WriteHandle> {
+        Ok(ChunkedWriteHandle {
+            store: self.store.clone(),
+            manifests: self.manifests.clone(),
+            min_blob_size: self.min_blob_size,
+            data: Pending::Whole(vec![]),
+        })
+    }
+
+    fn contains(&self, name: &str) -> Result<bool> {
+        if self.store.contains(name)? {
+            return Ok(true);
+        }
+
+        let Some(manifest) = self.manifest(name)? else {
+            return Ok(false);
+        };
+
+        for chunk in &manifest.chunks {
+            if !self.store.contains(&chunk.hash)? {
+                tracing::info!("Removing manifest of {name}, chunk {} is gone", chunk.hash);
+                if let Err(err) = self.manifests.delete(name) {
+                    tracing::warn!("Failed to remove manifest of {name}: {err}");
+                }
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Chunks are listed as blobs of their own, alongside the manifests of the
+    /// blobs that are made of them.
+    fn list(&self) -> Result<Blobs> {
+        let blobs = self.store.list()?.chain(self.manifests.list()?);
+        Ok(Box::new(blobs))
+    }
+
+    /// Chunks may be shared with other blobs, so they're left to be evicted.
+    fn delete(&self, name: &str) -> Result<()> {
+        let whole = self.store.delete(name);
+        let chunked = self.manifests.delete(name);
+
+        match (whole, chunked) {
+            (Err(Error::NotFound(_)), Err(Error::NotFound(_))) => {
+                Err(Error::not_found("file not found"))
+            }
+            (Err(Error::NotFound(_)), chunked) => chunked,
+            (whole, _) => whole,
+        }
+    }
+
+    /// Chunked blobs are kept by leasing their chunks.
+    fn lease(&self, names: &[&str]) -> Lease {
+        let mut chunks = vec![];
+        for name in names {
+            match self.manifest(name) {
+                Ok(Some(manifest)) => chunks.extend(manifest.chunks.into_iter().map(|c| c.hash)),
+                Ok(None) => {}
+                Err(err) => tracing::warn!("Failed to read manifest of {name}: {err}"),
+            }
+        }
+
+        let mut leased = names.to_vec();
+        leased.extend(chunks.iter().map(String::as_str));
+        self.store.lease(&leased)
+    }
+
+    fn chunks(&self, name: &str) -> Result<Option<Vec<Digest>>> {
+        Ok(self.manifest(name)?.map(|manifest| manifest.chunks))
+    }
+}
+
+/// The chunks that make up a blob, along with where each of them starts.
+struct Manifest {
+    chunks: Vec<Digest>,
+    offsets: Vec<u64>,
+    metadata: BlobMetadata,
+}
+
+impl Manifest {
+    fn new(manifest: SplitBlobResponse, metadata: BlobMetadata) -> Self {
+        let mut offsets = vec![];
+        let mut size = 0;
+        for chunk in &manifest.chunk_digests {
+            offsets.push(size);
+            size += chunk.size_bytes.max(0) as u64;
+        }
+
+        Self {
+            chunks: manifest.chunk_digests,
+            offsets,
+            metadata: BlobMetadata {
+                size,
+                compressed: false,
+                ..metadata
+            },
+        }
+    }
+}
+
+pub enum ChunkedReadHandle<S: Store> {
+    Whole(S::ReadHandle),
+    Chunked(ChunkReader<S>),
+}
+
+impl<S: Store> ReadHandle for ChunkedReadHandle<S> {
+    fn metadata(&self) -> Result<BlobMetadata> {
+        match self {
+            Self::Whole(reader) => reader.metadata(),
+            Self::Chunked(reader) => Ok(reader.manifest.metadata.clone()),
+        }
+    }
+}
+
+impl<S: Store> Read for ChunkedReadHandle<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Whole(reader) => reader.read(buf),
+            Self::Chunked(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<S: Store> Seek for ChunkedReadHandle<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Whole(reader) => reader.seek(pos),
+            Self::Chunked(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// Reads a chunked blob by reading each of its chunks in turn.
+pub struct ChunkReader<S: Store> {
+    store: S,
+    manifest: Manifest,
+    position: u64,
+    /// The chunk being read, along with its index.
+    current: Option<(usize, S::ReadHandle)>,
+}
+
+impl<S: Store> ChunkReader<S> {
+    fn new(store: S, manifest: Manifest) -> Self {
+        Self {
+            store,
+            manifest,
+            position: 0,
+            current: None,
+        }
+    }
+
+    /// Open the chunk that holds the current position.
+    fn open_chunk(&mut self) -> io::Result<&mut S::ReadHandle> {
+        let index = self
+            .manifest
+            .offsets
+            .partition_point(|&offset| offset <= self.position)
+            - 1;
+
+        if !matches!(self.current, Some((current, _)) if current == index) {
+            let chunk = &self.manifest.chunks[index];
+            let mut reader = self
+                .store
+                .read(&chunk.hash)
+                .map_err(|err| io::Error::new(ErrorKind::NotFound, err.to_string()))?;
+            reader.seek(SeekFrom::Start(
+                self.position - self.manifest.offsets[index],
+            ))?;
+            self.current = Some((index, reader));
+        }
+
+        Ok(&mut self.current.as_mut().unwrap().1)
+    }
+}
+
+impl<S: Store> Read for ChunkReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.manifest.metadata.size {
+            return Ok(0);
+        }
+
+        let read = self.open_chunk()?.read(buf)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "chunk is shorter than its digest",
+            ));
+        }
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<S: Store> Seek for ChunkReader<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.manifest.metadata.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        let position = position.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+
+        if position != self.position {
+            self.position = position;
+            self.current = None;
+        }
+
+        Ok(position)
+    }
+}
+
+/// Data written to a [`ChunkedWriteHandle`] so far.
+enum Pending {
+    /// The blob, while it's still small enough to be stored whole.
+    Whole(Vec<u8>),
+    /// The chunks of the blob that have been stored so far.
+    Chunked(Chunker, Vec<Digest>),
+}
+
+pub struct ChunkedWriteHandle<S, M> {
+    store: S,
+    manifests: M,
+    min_blob_size: u64,
+    data: Pending,
+}
+
+impl<S: Store, M: Store> WriteHandle for ChunkedWriteHandle<S, M> {
+    fn seal(self, name: &str) -> Result<()> {
+        match self.data {
+            Pending::Whole(data) => {
+                let mut writer = self.store.write()?;
+                writer.write_all(&data).map_err(Error::io)?;
+                writer.seal(name)
+            }
+            Pending::Chunked(chunker, mut chunks) => {
+                chunker.finish(|chunk| {
+                    chunks.push(store_chunk(&self.store, DigestFunction::Sha256, chunk)?);
+                    Ok(())
+                })?;
+
+                let manifest = SplitBlobResponse {
+                    chunk_digests: chunks,
+                    ..Default::default()
+                };
+                self.manifests.write_message(name, &manifest)
+            }
+        }
+    }
+
+    /// Chunks that were already stored are left to be evicted.
+    fn abort(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Store, M: Store> Write for ChunkedWriteHandle<S, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.data {
+            Pending::Whole(data) => {
+                data.extend_from_slice(buf);
+                if data.len() as u64 >= self.min_blob_size {
+                    let data = std::mem::take(data);
+                    self.data = Pending::Chunked(Chunker::new(), vec![]);
+                    self.write_chunks(&data)?;
+                }
+            }
+            Pending::Chunked(..) => self.write_chunks(buf)?,
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Store, M> ChunkedWriteHandle<S, M> {
+    fn write_chunks(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Pending::Chunked(chunker, chunks) = &mut self.data else {
+            unreachable!("blob is not being chunked");
+        };
+
+        chunker
+            .write(buf, |chunk| {
+                chunks.push(store_chunk(&self.store, DigestFunction::Sha256, chunk)?);
+                Ok(())
+            })
+            .map_err(|err| io::Error::other(err.to_string()))
+    }
+}
+
+/// Splits a stream of data into content-defined chunks, so that changing part
+/// of a blob only changes the chunks around the change.
+pub struct Chunker {
+    buffer: Vec<u8>,
+}
+
+impl Chunker {
+    pub fn new() -> Self {
+        Self { buffer: vec![] }
+    }
+
+    /// Add data to the stream, passing every chunk that is complete to `emit`.
+    pub fn write(&mut self, data: &[u8], emit: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+
+        // Wait for a few chunks' worth of data, rather than looking for cut
+        // points again after every write.
+        if self.buffer.len() < 4 * MAX_CHUNK_SIZE as usize {
+            return Ok(());
+        }
+
+        self.emit_chunks(false, emit)
+    }
+
+    /// Pass the rest of the stream to `emit`.
+    pub fn finish(mut self, emit: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        self.emit_chunks(true, emit)
+    }
+
+    fn emit_chunks(&mut self, all: bool, mut emit: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let mut emitted = 0;
+
+        for chunk in FastCDC::new(&self.buffer, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            // A cut point only depends on the data that follows the start of
+            // its chunk, up to the largest chunk size. Chunks with less data
+            // than that after their start might still grow.
+            if !all && chunk.offset + MAX_CHUNK_SIZE as usize > self.buffer.len() {
+                break;
+            }
+
+            emit(&self.buffer[chunk.offset..chunk.offset + chunk.length])?;
+            emitted = chunk.offset + chunk.length;
+        }
+
+        self.buffer.drain(..emitted);
+        Ok(())
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split the data from `reader` into chunks, storing each of them in `store`
+/// under its digest with `function`. Returns the digests of the chunks, in
+/// order.
+pub fn split<S: Store>(
+    store: &S,
+    function: DigestFunction,
+    mut reader: impl Read,
+) -> Result<Vec<Digest>> {
+    let mut chunker = Chunker::new();
+    let mut chunks = vec![];
+    let mut buf = vec![0; MAX_CHUNK_SIZE as usize];
+
+    loop {
+        let read = reader.read(&mut buf).map_err(Error::io)?;
+        if read == 0 {
+            break;
+        }
+
+        chunker.write(&buf[..read], |chunk| {
+            chunks.push(store_chunk(store, function, chunk)?);
+            Ok(())
+        })?;
+    }
+
+    chunker.finish(|chunk| {
+        chunks.push(store_chunk(store, function, chunk)?);
+        Ok(())
+    })?;
+
+    Ok(chunks)
+}
+
+/// Store a chunk under its digest, unless it's already stored.
+fn store_chunk<S: Store>(store: &S, function: DigestFunction, data: &[u8]) -> Result<Digest> {
+    let hash = hash::compute(function, data);
+    let name = hash.blob_name();
+
+    if !store.contains(&name)? {
+        let mut writer = store.write()?;
+        writer.write_all(data).map_err(Error::io)?;
+        writer.seal(&name)?;
+    }
+
+    Ok(Digest {
+        hash: hash.to_string(),
+        size_bytes: data.len() as i64,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mem::MemStore;
+
+    /// Data that doesn't repeat, so that chunk boundaries are spread out.
+    fn random_data(len: usize, mut seed: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            data.extend_from_slice(&seed.to_le_bytes());
+        }
+        data.truncate(len);
+        data
+    }
+
+    fn write(store: &impl Store, name: &str, data: &[u8]) {
+        let mut file = store.write().unwrap();
+        for chunk in data.chunks(100_000) {
+            file.write_all(chunk).unwrap();
+        }
+        file.seal(name).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_similar_blobs() {
+        let chunks = MemStore::new();
+        let store = ChunkedStore::new(chunks.clone(), MemStore::new()).with_min_blob_size(1024);
+
+        let original = random_data(8 * 1024 * 1024, 1);
+        let mut modified = original.clone();
+        modified.splice(3_000_000..3_000_010, [7; 100]);
+
+        write(&store, "original", &original);
+        write(&store, "modified", &modified);
+
+        let original_chunks = store.chunks("original").unwrap().unwrap();
+        let modified_chunks = store.chunks("modified").unwrap().unwrap();
+        let shared = modified_chunks
+            .iter()
+            .filter(|chunk| original_chunks.contains(chunk))
+            .count();
+        assert!(original_chunks.len() > 8);
+        assert!(shared >= modified_chunks.len() - 2);
+
+        let mut read = vec![];
+        store
+            .read("modified")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, modified);
+    }
+
+    #[test]
+    fn test_read_chunked_blob() {
+        let store = ChunkedStore::new(MemStore::new(), MemStore::new()).with_min_blob_size(1024);
+
+        let data = random_data(3 * 1024 * 1024 + 17, 2);
+        write(&store, "large", &data);
+        write(&store, "small", &[1, 2, 3]);
+
+        assert!(store.chunks("large").unwrap().is_some());
+        assert!(store.chunks("small").unwrap().is_none());
+
+        let mut reader = store.read("large").unwrap();
+        assert_eq!(reader.metadata().unwrap().size, data.len() as u64);
+
+        reader.seek(SeekFrom::Start(2_000_000)).unwrap();
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, &data[2_000_000..]);
+
+        let mut read = vec![];
+        store.read("small").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_missing_chunk() {
+        let chunks = MemStore::new();
+        let store = ChunkedStore::new(chunks.clone(), MemStore::new()).with_min_blob_size(1024);
+
+        write(&store, "large", &random_data(2 * 1024 * 1024, 3));
+        assert!(store.contains("large").unwrap());
+
+        let chunk = &store.chunks("large").unwrap().unwrap()[0];
+        chunks.delete(&chunk.hash).unwrap();
+
+        assert!(!store.contains("large").unwrap());
+        assert!(store.chunks("large").unwrap().is_none());
+    }
+}
@@ -1,40 +1,535 @@
-use super::{ReadHandle, Store, WriteHandle};
+use super::{BlobInfo, BlobMetadata, Blobs, ReadHandle, Store, WriteHandle};
+use crate::lease::{Lease, Leases};
 use crate::tee::TeeWriter;
 use bytes::BytesMut;
-use common::hash::Hasher;
+use common::config::Compression;
+use common::hash::{self, Hasher};
 use common::rand;
 use common::{Error, Result};
 use prost::Message;
 use proto::bazel::exec::Digest;
-use std::fs::{File, OpenOptions};
-use std::io::{copy, BufReader, BufWriter, ErrorKind, Read, Write};
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions, ReadDir};
+use std::io::{self, copy, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
+/// Suffix of the files that hold compressed blobs. Digests always refer to the
+/// uncompressed content, so a blob is found under either name.
+const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// Level used when compressing blobs at rest.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest possible size of a zstd frame header, which holds the size of the
+/// decompressed content.
+const ZSTD_MAX_FRAME_HEADER_SIZE: u64 = 18;
+
+/// Blobs are only kept compressed if that makes them at most this fraction of
+/// their original size. Anything else isn't worth decompressing on every read.
+const MAX_COMPRESSED_RATIO: f64 = 0.9;
+
+/// Version of the directory layout, recorded in [`LAYOUT_VERSION_FILE_NAME`].
+/// Version 1 was a single flat directory, from before the version was recorded.
+const LAYOUT_VERSION: u32 = 2;
+
+const LAYOUT_VERSION_FILE_NAME: &str = "layout-version";
+
+/// Subdirectory for files that are still being written. It's on the same
+/// filesystem as the blobs so that sealing a file is just a rename.
+const TEMP_DIR_NAME: &str = "tmp";
+
+/// Subdirectory that [`FileStore::scrub`] moves bad blobs into, so that they
+/// can be inspected.
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// Blobs are sharded into two levels of subdirectories named after the first
+/// characters of their name, e.g. `ab/cd/abcd…`, to keep directories small.
+///
+/// The modification time of a blob doubles as its last access time: it's
+/// updated whenever the blob is read or looked up, so that garbage collection
+/// can evict the least recently used blobs.
+///
+/// Blobs are written to temporary files in `tmp/` and only renamed into their
+/// shard once sealed, so a crash never leaves a partial blob behind.
 #[derive(Debug, Clone, PartialEq)]
 pub struct FileStore {
     dir: PathBuf,
+    compression: Compression,
+    sync: bool,
+    leases: Leases,
+}
+
+/// Limits on the space used by a [`FileStore`], enforced by
+/// [`FileStore::collect_garbage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcPolicy {
+    /// Largest total size of the stored blobs.
+    pub max_size_bytes: Option<u64>,
+    /// Free space to leave on the filesystem that holds the store.
+    pub min_free_bytes: Option<u64>,
+}
+
+/// Outcome of a [`FileStore::collect_garbage`] run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub evicted_blobs: u64,
+    pub reclaimed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// Outcome of a [`FileStore::scrub`] run. Every bad blob is moved into the
+/// quarantine directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub checked_blobs: u64,
+    /// Blobs whose content doesn't match their name.
+    pub corrupt: Vec<PathBuf>,
+    /// Files that aren't named after a digest, or are in the wrong shard.
+    pub misnamed: Vec<PathBuf>,
+}
+
+/// A blob found by a [`BlobWalker`].
+struct StoredBlob {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Lists the blobs of a [`FileStore`] by walking its shard directories one
+/// at a time, so that even a huge store can be listed in little memory.
+struct BlobWalker {
+    shards: Vec<PathBuf>,
+    subshards: Vec<PathBuf>,
+    entries: Option<ReadDir>,
+}
+
+impl BlobWalker {
+    fn new(dir: &Path) -> Result<Self> {
+        // Other subdirectories, like the one for temporary files, have longer
+        // names than shards.
+        let shards = subdirs(dir)?
+            .into_iter()
+            .filter(|shard| shard.file_name().is_some_and(|name| name.len() <= 2))
+            .collect();
+
+        Ok(Self {
+            shards,
+            subshards: vec![],
+            entries: None,
+        })
+    }
+
+    fn next_blob(&mut self) -> Result<Option<StoredBlob>> {
+        loop {
+            if let Some(entries) = &mut self.entries {
+                match entries.next() {
+                    Some(entry) => {
+                        if let Some(blob) = stored_blob(entry.map_err(Error::io)?)? {
+                            return Ok(Some(blob));
+                        }
+                        continue;
+                    }
+                    None => self.entries = None,
+                }
+            }
+
+            if let Some(subshard) = self.subshards.pop() {
+                self.entries = read_dir_if_exists(&subshard)?;
+            } else if let Some(shard) = self.shards.pop() {
+                self.subshards = subdirs(&shard)?;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl Iterator for BlobWalker {
+    type Item = Result<StoredBlob>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_blob().transpose()
+    }
+}
+
+fn stored_blob(entry: std::fs::DirEntry) -> Result<Option<StoredBlob>> {
+    let metadata = match entry.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        // It was removed while listing.
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::io(err)),
+    };
+
+    let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+        return Ok(None);
+    };
+
+    let name = file_name
+        .strip_suffix(COMPRESSED_SUFFIX)
+        .unwrap_or(&file_name)
+        .to_owned();
+
+    Ok(Some(StoredBlob {
+        name,
+        path: entry.path(),
+        size: metadata.len(),
+        modified: metadata.modified().map_err(Error::io)?,
+    }))
+}
+
+fn read_dir_if_exists(dir: &Path) -> Result<Option<ReadDir>> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => Ok(Some(entries)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(Error::io(err)),
+    }
 }
 
 impl FileStore {
     /// Create a new [`FileStore`] instance that uses the local disk.
     pub fn new(dir: PathBuf) -> Self {
-        Self { dir }
+        Self {
+            dir,
+            compression: Compression::None,
+            sync: true,
+            leases: Leases::default(),
+        }
+    }
+
+    /// Open a [`FileStore`] in `dir`, creating it if needed. A directory
+    /// still using the old flat layout is migrated to the current one first.
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        let store = Self::new(dir);
+        std::fs::create_dir_all(store.temp_dir()).map_err(Error::io)?;
+
+        let version_path = store.dir.join(LAYOUT_VERSION_FILE_NAME);
+        match std::fs::read_to_string(&version_path) {
+            Ok(version) if version.trim() == LAYOUT_VERSION.to_string() => {}
+            Ok(version) => {
+                return Err(Error::runtime(&format!(
+                    "unsupported storage layout version {} in {:?}",
+                    version.trim(),
+                    store.dir
+                )));
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                store.migrate_flat_layout()?;
+                std::fs::write(&version_path, format!("{LAYOUT_VERSION}\n")).map_err(Error::io)?;
+            }
+            Err(err) => return Err(Error::io(err)),
+        }
+
+        Ok(store)
+    }
+
+    /// Compress blobs as they're sealed. Blobs that are already stored are
+    /// left as they are, and can be read either way.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Whether blobs are flushed to disk before `seal` returns. Without it, a
+    /// crash can lose or truncate recently sealed blobs.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Remove the temporary files of uploads that were never sealed, e.g.
+    /// because the server crashed. This must only be called while nothing is
+    /// writing to the store.
+    pub fn remove_temp_files(&self) -> Result<u64> {
+        let Some(entries) = read_dir_if_exists(&self.temp_dir())? else {
+            return Ok(0);
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry.map_err(Error::io)?;
+            if entry.file_type().map_err(Error::io)?.is_file() {
+                std::fs::remove_file(entry.path()).map_err(Error::io)?;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            tracing::info!("Removed {removed} leftover temporary files");
+        }
+
+        Ok(removed)
+    }
+
+    /// Move the blobs of a flat storage directory into their shards. Leftover
+    /// temporary files belong to uploads that can no longer be finished, so
+    /// they're removed. Subdirectories are left alone.
+    fn migrate_flat_layout(&self) -> Result<()> {
+        let mut migrated = 0;
+
+        for entry in std::fs::read_dir(&self.dir).map_err(Error::io)? {
+            let entry = entry.map_err(Error::io)?;
+            if !entry.file_type().map_err(Error::io)?.is_file() {
+                continue;
+            }
+
+            let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+
+            if file_name.starts_with("tmp-") {
+                std::fs::remove_file(entry.path()).map_err(Error::io)?;
+                continue;
+            }
+
+            let name = file_name
+                .strip_suffix(COMPRESSED_SUFFIX)
+                .unwrap_or(&file_name);
+
+            let shard = shard_dir(&self.dir, name);
+            std::fs::create_dir_all(&shard).map_err(Error::io)?;
+            std::fs::rename(entry.path(), shard.join(&file_name)).map_err(Error::io)?;
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            tracing::info!(
+                "Migrated {migrated} blobs in {:?} to sharded layout",
+                self.dir
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Evict the least recently used blobs until the store is within
+    /// `policy`. Leased blobs are never evicted, nor are blobs that are used
+    /// while the collection is running.
+    pub fn collect_garbage(&self, policy: &GcPolicy) -> Result<GcReport> {
+        let mut blobs = BlobWalker::new(&self.dir)?.collect::<Result<Vec<_>>>()?;
+        let total_size = blobs.iter().map(|blob| blob.size).sum();
+
+        let mut excess = policy
+            .max_size_bytes
+            .map_or(0, |max_size| u64::saturating_sub(total_size, max_size));
+
+        if let Some(min_free) = policy.min_free_bytes {
+            let free = available_space(&self.dir).map_err(Error::io)?;
+            excess = excess.max(min_free.saturating_sub(free));
+        }
+
+        let mut report = GcReport {
+            remaining_bytes: total_size,
+            ..Default::default()
+        };
+
+        blobs.sort_by_key(|blob| blob.modified);
+        for blob in blobs {
+            if report.reclaimed_bytes >= excess {
+                break;
+            }
+
+            let evicted = self
+                .leases
+                .unless_leased(&blob.name, || evict(&blob))
+                .transpose()
+                .map_err(Error::io)?;
+
+            if evicted == Some(true) {
+                report.evicted_blobs += 1;
+                report.reclaimed_bytes += blob.size;
+                report.remaining_bytes -= blob.size;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Check every blob against its name, and move the ones that don't match
+    /// into quarantine. Only content-addressed stores can be scrubbed.
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let quarantine_dir = self.dir.join(QUARANTINE_DIR_NAME);
+        let mut report = ScrubReport::default();
+
+        for blob in BlobWalker::new(&self.dir)? {
+            let blob = blob?;
+            report.checked_blobs += 1;
+
+            let misnamed = hash::Digest::from_blob_name(&blob.name).is_err()
+                || blob.path.parent() != Some(shard_dir(&self.dir, &blob.name).as_path());
+
+            let bad = if misnamed {
+                &mut report.misnamed
+            } else if !hashes_to_name(&blob)? {
+                &mut report.corrupt
+            } else {
+                continue;
+            };
+
+            tracing::warn!("Quarantining {:?}", blob.path);
+            quarantine(&blob.path, &quarantine_dir)?;
+            bad.push(blob.path);
+        }
+
+        Ok(report)
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        self.dir.join(TEMP_DIR_NAME)
     }
 
     fn local_path(&self, name: &str) -> PathBuf {
-        let mut path = self.dir.clone();
-        path.push(name);
-        path
+        shard_dir(&self.dir, name).join(name)
+    }
+
+    fn compressed_path(&self, name: &str) -> PathBuf {
+        shard_dir(&self.dir, name).join(format!("{name}{COMPRESSED_SUFFIX}"))
+    }
+}
+
+/// Remove a blob found by [`FileStore::stored_blobs`], unless it has been used
+/// since then. Returns whether it was removed.
+fn evict(blob: &StoredBlob) -> io::Result<bool> {
+    let modified = match std::fs::metadata(&blob.path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    if modified != blob.modified {
+        return Ok(false);
+    }
+
+    match std::fs::remove_file(&blob.path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Refuse names that could resolve to a path outside of their shard, such as
+/// ones with separators or dots in them. Blobs are looked up by names that
+/// clients send, so this keeps a bad name from reaching other files.
+fn check_name(name: &str) -> Result<()> {
+    let valid = name
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+
+    if name.is_empty() || !valid {
+        return Err(Error::invalid(&format!("invalid blob name: {name:?}")));
+    }
+
+    Ok(())
+}
+
+/// Whether the content of a blob still hashes to its name. Compressed data
+/// that can't be decompressed doesn't.
+fn hashes_to_name(blob: &StoredBlob) -> Result<bool> {
+    let file = match File::open(&blob.path) {
+        Ok(file) => file,
+        // It was removed while scrubbing.
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(Error::io(err)),
+    };
+
+    let function = hash::Digest::from_blob_name(&blob.name)?.function();
+    let mut hasher = Hasher::new(function);
+    let hashed = if blob.path.to_string_lossy().ends_with(COMPRESSED_SUFFIX) {
+        zstd::stream::read::Decoder::new(file)
+            .and_then(|mut decoder| copy(&mut decoder, &mut hasher))
+    } else {
+        copy(&mut BufReader::new(file), &mut hasher)
+    };
+
+    match hashed {
+        Ok(_) => Ok(hasher.finish().blob_name() == blob.name),
+        Err(err) if err.kind() == ErrorKind::InvalidData || err.kind() == ErrorKind::Other => {
+            Ok(false)
+        }
+        Err(err) => Err(Error::io(err)),
+    }
+}
+
+/// Move a file into the quarantine directory, without replacing anything
+/// that is already there.
+fn quarantine(path: &Path, quarantine_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(quarantine_dir).map_err(Error::io)?;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::invalid("file has no name"))?
+        .to_string_lossy();
+
+    let mut dest = quarantine_dir.join(file_name.as_ref());
+    while dest.exists() {
+        dest = quarantine_dir.join(format!("{file_name}-{}", rand::string(6)));
+    }
+
+    std::fs::rename(path, dest).map_err(Error::io)
+}
+
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    let Some(entries) = read_dir_if_exists(dir)? else {
+        return Ok(dirs);
+    };
+
+    for entry in entries {
+        let entry = entry.map_err(Error::io)?;
+        if entry.file_type().map_err(Error::io)?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Free space on the filesystem that holds `path`, that is available to
+/// unprivileged users.
+fn available_space(path: &Path) -> io::Result<u64> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// Flush the entries of a directory to disk.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Record that a blob has just been used.
+fn touch(file: &File) {
+    if let Err(err) = file.set_modified(SystemTime::now()) {
+        tracing::warn!("Failed to update blob access time: {err}");
     }
 }
 
+/// Directory that holds the blob `name` in the store rooted at `dir`.
+fn shard_dir(dir: &Path, name: &str) -> PathBuf {
+    let mut chars = name.chars();
+    let first = chars.by_ref().take(2).collect::<String>();
+    let second = chars.take(2).collect::<String>();
+    dir.join(first).join(second)
+}
+
 impl Store for FileStore {
     type ReadHandle = FileReadHandle;
 
     fn read(&self, name: &str) -> Result<Self::ReadHandle> {
-        let path = self.local_path(name);
+        check_name(name)?;
+
+        match OpenOptions::new().read(true).open(self.local_path(name)) {
+            Ok(file) => {
+                touch(&file);
+                return Ok(FileReadHandle {
+                    inner: ReadInner::Plain(file),
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::io(err)),
+        }
 
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
+        let reader = ZstdReader::open(self.compressed_path(name)).map_err(|err| {
             if err.kind() == ErrorKind::NotFound {
                 Error::not_found("file not found")
             } else {
@@ -42,15 +537,18 @@ impl Store for FileStore {
             }
         })?;
 
-        Ok(FileReadHandle { file })
+        Ok(FileReadHandle {
+            inner: ReadInner::Zstd(reader),
+        })
     }
 
     type WriteHandle = FileWriteHandle;
 
     fn write(&self) -> Result<Self::WriteHandle> {
-        let temp_name = format!("tmp-{}", rand::string(20));
-        let path = self.local_path(&temp_name);
-        println!("path: {path:?}");
+        let temp_dir = self.temp_dir();
+        std::fs::create_dir_all(&temp_dir).map_err(Error::io)?;
+
+        let path = temp_dir.join(format!("tmp-{}", rand::string(20)));
 
         let file = OpenOptions::new()
             .create(true)
@@ -58,50 +556,281 @@ impl Store for FileStore {
             .open(&path)
             .map_err(Error::io)?;
 
-        Ok(FileWriteHandle { path, file })
+        Ok(FileWriteHandle {
+            dir: self.dir.clone(),
+            path,
+            file,
+            compression: self.compression,
+            sync: self.sync,
+            finished: false,
+        })
     }
 
     fn contains(&self, name: &str) -> Result<bool> {
-        let path = self.local_path(&name);
-        match OpenOptions::new().read(true).open(path) {
-            Ok(_) => Ok(true),
-            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
-            Err(err) => Err(Error::io(err)),
+        check_name(name)?;
+
+        for path in [self.local_path(name), self.compressed_path(name)] {
+            match OpenOptions::new().read(true).open(path) {
+                Ok(file) => {
+                    touch(&file);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::io(err)),
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn list(&self) -> Result<Blobs> {
+        let blobs = BlobWalker::new(&self.dir)?.map(|blob| {
+            blob.map(|blob| BlobInfo {
+                name: blob.name,
+                size: blob.size,
+                accessed: blob.modified,
+            })
+        });
+
+        Ok(Box::new(blobs))
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+
+        let mut deleted = false;
+        for path in [self.local_path(name), self.compressed_path(name)] {
+            match std::fs::remove_file(path) {
+                Ok(()) => deleted = true,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::io(err)),
+            }
+        }
+
+        if !deleted {
+            return Err(Error::not_found("file not found"));
         }
+
+        Ok(())
+    }
+
+    fn lease(&self, names: &[&str]) -> Lease {
+        self.leases.acquire(names)
     }
 }
 
 pub struct FileReadHandle {
-    file: File,
+    inner: ReadInner,
+}
+
+enum ReadInner {
+    Plain(File),
+    Zstd(ZstdReader),
 }
 
 impl ReadHandle for FileReadHandle {
-    fn metadata(&self) {
-        todo!()
+    /// The modification time of the file is its last access time. Where the
+    /// filesystem doesn't record creation times, that's used for both.
+    fn metadata(&self) -> Result<BlobMetadata> {
+        let (file, compressed) = match &self.inner {
+            ReadInner::Plain(file) => (file, false),
+            ReadInner::Zstd(reader) => (reader.decoder.get_ref().get_ref(), true),
+        };
+
+        let metadata = file.metadata().map_err(Error::io)?;
+        let accessed = metadata.modified().map_err(Error::io)?;
+
+        let size = match &self.inner {
+            ReadInner::Plain(_) => metadata.len(),
+            ReadInner::Zstd(reader) => reader.content_size().map_err(Error::io)?,
+        };
+
+        Ok(BlobMetadata {
+            size,
+            created: metadata.created().unwrap_or(accessed),
+            accessed,
+            compressed,
+        })
     }
 }
 
 impl Read for FileReadHandle {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.file.read(buf)
+        match &mut self.inner {
+            ReadInner::Plain(file) => file.read(buf),
+            ReadInner::Zstd(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for FileReadHandle {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match &mut self.inner {
+            ReadInner::Plain(file) => file.seek(pos),
+            ReadInner::Zstd(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// Decompresses a blob as it's read. Compressed data can't be seeked into, so
+/// seeking decompresses up to the new position, starting over from the
+/// beginning of the file when seeking backwards.
+struct ZstdReader {
+    path: PathBuf,
+    decoder: zstd::stream::read::Decoder<'static, BufReader<File>>,
+    position: u64,
+}
+
+impl ZstdReader {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        touch(&file);
+        let decoder = zstd::stream::read::Decoder::new(file)?;
+
+        Ok(Self {
+            path,
+            decoder,
+            position: 0,
+        })
+    }
+
+    /// Size of the decompressed blob. It's recorded in the frame header when
+    /// the blob is compressed, and otherwise found by decompressing it all.
+    fn content_size(&self) -> io::Result<u64> {
+        let mut header = vec![];
+        File::open(&self.path)?
+            .take(ZSTD_MAX_FRAME_HEADER_SIZE)
+            .read_to_end(&mut header)?;
+
+        if let Ok(Some(size)) = zstd::zstd_safe::get_frame_content_size(&header) {
+            return Ok(size);
+        }
+
+        let mut decoder = zstd::stream::read::Decoder::new(File::open(&self.path)?)?;
+        copy(&mut decoder, &mut io::sink())
+    }
+
+    fn seek_to(&mut self, position: u64) -> io::Result<u64> {
+        if position < self.position {
+            *self = Self::open(self.path.clone())?;
+        }
+
+        let skip = position - self.position;
+        copy(&mut self.take(skip), &mut io::sink())?;
+        Ok(self.position)
+    }
+}
+
+impl Read for ZstdReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.decoder.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ZstdReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let invalid = || io::Error::new(ErrorKind::InvalidInput, "invalid seek position");
+
+        match pos {
+            SeekFrom::Start(position) => self.seek_to(position),
+            SeekFrom::Current(offset) => {
+                let position = self
+                    .position
+                    .checked_add_signed(offset)
+                    .ok_or_else(invalid)?;
+                self.seek_to(position)
+            }
+            SeekFrom::End(offset) => {
+                // The size is only known once everything has been decompressed.
+                let size = self.seek_to(u64::MAX)?;
+                let position = size.checked_add_signed(offset).ok_or_else(invalid)?;
+                self.seek_to(position)
+            }
+        }
     }
 }
 
+/// Handle to a temporary file that becomes a blob once sealed. The file is
+/// removed if the handle is dropped without being sealed.
 pub struct FileWriteHandle {
+    dir: PathBuf,
     path: PathBuf,
     file: File,
+    compression: Compression,
+    sync: bool,
+    /// Whether the temporary file has been moved into place or removed.
+    finished: bool,
+}
+
+impl FileWriteHandle {
+    fn persist(&mut self, name: &str) -> Result<()> {
+        check_name(name)?;
+
+        let dir = shard_dir(&self.dir, name);
+        let new_dir = !dir.exists();
+        std::fs::create_dir_all(&dir).map_err(Error::io)?;
+
+        let (path, new_path) = match self.compression {
+            Compression::Zstd => match compress_file(&self.path, self.sync)? {
+                Some(compressed_path) => {
+                    std::fs::remove_file(&self.path).map_err(Error::io)?;
+                    let compressed_name = format!("{name}{COMPRESSED_SUFFIX}");
+                    (compressed_path, dir.join(compressed_name))
+                }
+                None => (self.path.clone(), dir.join(name)),
+            },
+            Compression::None => (self.path.clone(), dir.join(name)),
+        };
+
+        if self.sync && path == self.path {
+            self.file.sync_all().map_err(Error::io)?;
+        }
+
+        if let Err(err) = std::fs::rename(&path, new_path) {
+            let _ = std::fs::remove_file(&path);
+            return Err(Error::io(err));
+        }
+        self.finished = true;
+
+        if self.sync {
+            // The rename is only durable once the directories that hold the
+            // blob are, including any shards that were just created.
+            sync_dir(&dir).map_err(Error::io)?;
+            if new_dir {
+                for dir in dir.ancestors().skip(1).take(2) {
+                    sync_dir(dir).map_err(Error::io)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl WriteHandle for FileWriteHandle {
-    fn seal(self, name: &str) -> Result<()> {
-        let mut new_path = self
-            .path
-            .parent()
-            .ok_or_else(|| Error::invalid("file has no parent directory"))?
-            .to_owned();
+    fn seal(mut self, name: &str) -> Result<()> {
+        self.persist(name)
+    }
+
+    fn abort(mut self) -> Result<()> {
+        self.finished = true;
+        std::fs::remove_file(&self.path).map_err(Error::io)
+    }
+}
 
-        new_path.push(name);
-        std::fs::rename(self.path, new_path).map_err(Error::io)
+impl Drop for FileWriteHandle {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != ErrorKind::NotFound {
+                tracing::warn!("Failed to remove temporary file {:?}: {err}", self.path);
+            }
+        }
     }
 }
 
@@ -115,6 +844,46 @@ impl Write for FileWriteHandle {
     }
 }
 
+/// Write a compressed copy of the file at `path` next to it. Returns the path
+/// of the copy, or `None` if compressing the file didn't save enough space to
+/// be worth it. The copy is flushed to disk if `sync` is set.
+fn compress_file(path: &Path, sync: bool) -> Result<Option<PathBuf>> {
+    let size = std::fs::metadata(path).map_err(Error::io)?.len();
+    let compressed_path = path.with_extension(&COMPRESSED_SUFFIX[1..]);
+
+    let compressed_size = match write_compressed(path, &compressed_path, size, sync) {
+        Ok(compressed_size) => compressed_size,
+        Err(err) => {
+            let _ = std::fs::remove_file(&compressed_path);
+            return Err(Error::io(err));
+        }
+    };
+
+    if compressed_size as f64 > size as f64 * MAX_COMPRESSED_RATIO {
+        std::fs::remove_file(&compressed_path).map_err(Error::io)?;
+        return Ok(None);
+    }
+
+    Ok(Some(compressed_path))
+}
+
+fn write_compressed(src: &Path, dst: &Path, size: u64, sync: bool) -> io::Result<u64> {
+    let mut input = File::open(src)?;
+    let output = BufWriter::new(File::create(dst)?);
+
+    let mut encoder = zstd::stream::write::Encoder::new(output, ZSTD_LEVEL)?;
+    encoder.set_pledged_src_size(Some(size))?;
+    copy(&mut input, &mut encoder)?;
+
+    let output = encoder.finish()?;
+    let output = output.into_inner().map_err(|err| err.into_error())?;
+    if sync {
+        output.sync_all()?;
+    }
+
+    Ok(std::fs::metadata(dst)?.len())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -138,6 +907,325 @@ mod test {
         std::fs::remove_dir_all(&dir).unwrap();
     }
 
+    #[test]
+    fn test_seek() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone());
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3, 4]).unwrap();
+            file.seal("foo").unwrap();
+        }
+
+        let mut file = store.read("foo").unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![3, 4]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_abort_removes_temp_file() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone());
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.abort().unwrap();
+        }
+
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_removes_temp_file() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone());
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+        }
+
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let dir = create_temp_dir();
+        let store = FileStore::open(dir.clone()).unwrap().with_sync(false);
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.seal("foo").unwrap();
+        }
+        std::fs::write(dir.join("tmp/tmp-leftover"), [4, 5, 6]).unwrap();
+
+        assert_eq!(store.remove_temp_files().unwrap(), 1);
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        assert!(store.contains("foo").unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_read_and_write() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone()).with_compression(Compression::Zstd);
+        let data = b"hello world ".repeat(1000);
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&data).unwrap();
+            file.seal("foo").unwrap();
+        }
+
+        let stored = std::fs::metadata(dir.join("fo/o/foo.zst")).unwrap();
+        assert!(stored.len() < data.len() as u64);
+        assert!(store.contains("foo").unwrap());
+
+        let mut file = store.read("foo").unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        file.seek(SeekFrom::Start(6)).unwrap();
+        let mut read = [0; 5];
+        file.read_exact(&mut read).unwrap();
+        assert_eq!(&read, b"world");
+
+        assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), data.len() as u64 - 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incompressible_blob_stays_uncompressed() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone()).with_compression(Compression::Zstd);
+
+        // Bytes from a simple xorshift generator don't compress at all.
+        let mut state: u32 = 0x9e37_79b9;
+        let data = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&data).unwrap();
+            file.seal("foo").unwrap();
+        }
+
+        assert_eq!(std::fs::read(dir.join("fo/o/foo")).unwrap(), data);
+        assert_eq!(std::fs::read_dir(dir.join("fo/o")).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_flat_layout() {
+        let dir = create_temp_dir();
+        std::fs::write(dir.join("abcdef"), [1, 2, 3]).unwrap();
+        std::fs::write(dir.join("tmp-leftover"), [4, 5, 6]).unwrap();
+        std::fs::create_dir(dir.join("action-cache")).unwrap();
+
+        let store = FileStore::open(dir.clone()).unwrap();
+
+        assert!(dir.join("ab/cd/abcdef").exists());
+        assert!(!dir.join("abcdef").exists());
+        assert!(!dir.join("tmp-leftover").exists());
+        assert!(dir.join("action-cache").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("layout-version")).unwrap(),
+            "2\n"
+        );
+
+        let mut file = store.read("abcdef").unwrap();
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1, 2, 3]);
+
+        // Opening it again leaves it as it is.
+        FileStore::open(dir.clone()).unwrap();
+        assert!(dir.join("ab/cd/abcdef").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let dir = create_temp_dir();
+        let store = FileStore::open(dir.clone()).unwrap();
+        let now = SystemTime::now();
+
+        for (i, name) in ["aaaa", "bbbb", "cccc", "dddd"].iter().enumerate() {
+            let mut file = store.write().unwrap();
+            file.write_all(&[0; 100]).unwrap();
+            file.seal(name).unwrap();
+
+            // Make the blobs look like they were last used in order.
+            let file = File::open(store.local_path(name)).unwrap();
+            let age = std::time::Duration::from_secs(100 - i as u64);
+            file.set_modified(now - age).unwrap();
+        }
+
+        let lease = store.lease(&["aaaa"]);
+        let policy = GcPolicy {
+            max_size_bytes: Some(250),
+            min_free_bytes: None,
+        };
+
+        let report = store.collect_garbage(&policy).unwrap();
+        assert_eq!(
+            report,
+            GcReport {
+                evicted_blobs: 2,
+                reclaimed_bytes: 200,
+                remaining_bytes: 200,
+            }
+        );
+
+        assert!(store.contains("aaaa").unwrap());
+        assert!(!store.contains("bbbb").unwrap());
+        assert!(!store.contains("cccc").unwrap());
+        assert!(store.contains("dddd").unwrap());
+
+        drop(lease);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_and_delete() {
+        let dir = create_temp_dir();
+        let store = FileStore::open(dir.clone()).unwrap();
+
+        for name in ["aaaa", "bbbb"] {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.seal(name).unwrap();
+        }
+
+        // Unsealed files aren't listed.
+        let mut pending = store.write().unwrap();
+        pending.write_all(&[4, 5, 6]).unwrap();
+
+        let mut blobs = store
+            .list()
+            .unwrap()
+            .map(|blob| blob.map(|blob| (blob.name, blob.size)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        blobs.sort();
+        assert_eq!(blobs, vec![("aaaa".into(), 3), ("bbbb".into(), 3)]);
+
+        store.delete("aaaa").unwrap();
+        assert!(!store.contains("aaaa").unwrap());
+        assert_eq!(store.list().unwrap().count(), 1);
+        assert!(matches!(store.delete("aaaa"), Err(Error::NotFound(_))));
+
+        pending.abort().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.clone());
+        let compressed_store = store.clone().with_compression(Compression::Zstd);
+        let data = b"hello world ".repeat(1000);
+
+        for (store, name) in [(&store, "plain"), (&compressed_store, "compressed")] {
+            let mut file = store.write().unwrap();
+            file.write_all(&data).unwrap();
+            file.seal(name).unwrap();
+        }
+
+        let before = SystemTime::now();
+        let plain = store.read("plain").unwrap().metadata().unwrap();
+        assert_eq!(plain.size, data.len() as u64);
+        assert!(!plain.compressed);
+        assert!(plain.accessed >= before);
+        assert!(plain.created <= plain.accessed);
+
+        let compressed = store.read("compressed").unwrap().metadata().unwrap();
+        assert_eq!(compressed.size, data.len() as u64);
+        assert!(compressed.compressed);
+        assert!(compressed.accessed >= before);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_scrub() {
+        let dir = create_temp_dir();
+        let store = FileStore::open(dir.clone()).unwrap();
+        let compressed_store = store.clone().with_compression(Compression::Zstd);
+
+        let hash = |data: &[u8]| {
+            let mut hasher = Hasher::sha256();
+            hasher.write_all(data).unwrap();
+            hasher.finish().to_string()
+        };
+
+        let good = b"good ".repeat(1000);
+        let bad = b"bad ".repeat(1000);
+        for (store, name, data) in [
+            (&store, hash(&good), &good),
+            (&compressed_store, hash(&bad), &good),
+            (&store, "foo".to_string(), &good),
+        ] {
+            let mut file = store.write().unwrap();
+            file.write_all(data).unwrap();
+            file.seal(&name).unwrap();
+        }
+
+        let report = store.scrub().unwrap();
+        assert_eq!(report.checked_blobs, 3);
+        assert_eq!(report.corrupt, vec![store.compressed_path(&hash(&bad))]);
+        assert_eq!(report.misnamed, vec![store.local_path("foo")]);
+
+        assert!(store.contains(&hash(&good)).unwrap());
+        assert!(!store.contains(&hash(&bad)).unwrap());
+        assert!(!store.contains("foo").unwrap());
+        assert_eq!(std::fs::read(dir.join("quarantine/foo")).unwrap(), good);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_unsafe_names() {
+        let dir = create_temp_dir();
+        let store = FileStore::new(dir.join("store"));
+        std::fs::write(dir.join("secret"), [1, 2, 3]).unwrap();
+
+        for name in ["../../secret", "..", "ab/../../secret", ""] {
+            assert!(matches!(store.read(name), Err(Error::InvalidArgument(_))));
+            assert!(matches!(
+                store.contains(name),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(matches!(store.delete(name), Err(Error::InvalidArgument(_))));
+
+            let file = store.write().unwrap();
+            assert!(matches!(file.seal(name), Err(Error::InvalidArgument(_))));
+        }
+
+        assert_eq!(std::fs::read(dir.join("secret")).unwrap(), vec![1, 2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     fn create_temp_dir() -> PathBuf {
         let mut path = std::env::temp_dir();
         path.push(format!("rust-test-{}", rand::string(20)));
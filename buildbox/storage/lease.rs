@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Blobs that are in use and must not be evicted, counted by how many
+/// [`Lease`]s hold each of them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Leases(Arc<Mutex<HashMap<String, usize>>>);
+
+impl Leases {
+    /// Lease the given blobs until the returned [`Lease`] is dropped.
+    pub fn acquire(&self, names: &[&str]) -> Lease {
+        let mut leases = self.0.lock().unwrap();
+        for name in names {
+            *leases.entry(name.to_string()).or_default() += 1;
+        }
+
+        Lease {
+            leases: Some(self.clone()),
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Run `f` unless the blob is leased. The blob can't be leased while `f`
+    /// runs, so this can be used to remove it safely.
+    pub fn unless_leased<T>(&self, name: &str, f: impl FnOnce() -> T) -> Option<T> {
+        let leases = self.0.lock().unwrap();
+        if leases.contains_key(name) {
+            return None;
+        }
+
+        Some(f())
+    }
+}
+
+impl PartialEq for Leases {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Keeps blobs from being evicted from a store for as long as it's held.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Lease {
+    leases: Option<Leases>,
+    names: Vec<String>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let Some(leases) = &self.leases else {
+            return;
+        };
+
+        let mut leases = leases.0.lock().unwrap();
+        for name in &self.names {
+            if let Some(count) = leases.get_mut(name) {
+                *count -= 1;
+                if *count == 0 {
+                    leases.remove(name);
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,14 @@
 pub(crate) mod tee;
+pub(crate) mod lease;
 pub(crate) mod store;
 pub(crate) mod proto;
 
 pub mod mem;
 pub mod file;
+pub mod verify;
+pub mod tiered;
+pub mod chunked;
 
-pub use store::{Store, ReadHandle, WriteHandle};
+pub use store::{BlobInfo, BlobMetadata, Blobs, Store, ReadHandle, WriteHandle};
+pub use lease::Lease;
 pub use proto::ProtoStoreExt;
\ No newline at end of file
@@ -1,18 +1,17 @@
-use super::{ReadHandle, Store, WriteHandle};
+use super::{BlobInfo, BlobMetadata, Blobs, ReadHandle, Store, WriteHandle};
 use common::hash::Digest;
 use common::hash::Hasher;
 use common::{Error, Result};
 use prost::Message;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::sync::MutexGuard;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tracing::instrument::WithDispatch;
 use tracing::Instrument;
 
-type SharedMap<K, T> = Arc<Mutex<HashMap<K, T>>>;
-
 #[derive(Debug, Clone)]
 struct SharedVec<T>(Arc<Vec<T>>);
 
@@ -22,17 +21,123 @@ impl AsRef<[u8]> for SharedVec<u8> {
     }
 }
 
+/// A stored blob, along with when it was last used.
+#[derive(Debug, Clone)]
+struct MemEntry {
+    data: SharedVec<u8>,
+    created: SystemTime,
+    accessed: SystemTime,
+    /// Position of the entry in [`MemInner::lru`].
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemInner {
+    entries: HashMap<String, MemEntry>,
+    /// Names of the entries, from least to most recently used.
+    lru: BTreeMap<u64, String>,
+    /// Incremented whenever an entry is used, to order them in `lru`.
+    clock: u64,
+    /// Total size of the entries.
+    size: u64,
+}
+
+impl MemInner {
+    /// Look up an entry, and mark it as the most recently used one.
+    fn touch(&mut self, name: &str) -> Option<&MemEntry> {
+        let entry = self.entries.get_mut(name)?;
+        self.lru.remove(&entry.last_use);
+
+        self.clock += 1;
+        entry.last_use = self.clock;
+        entry.accessed = SystemTime::now();
+        self.lru.insert(entry.last_use, name.to_string());
+
+        Some(entry)
+    }
+
+    fn insert(&mut self, name: &str, mut entry: MemEntry) {
+        self.remove(name);
+
+        self.clock += 1;
+        entry.last_use = self.clock;
+        self.size += entry.data.0.len() as u64;
+        self.lru.insert(entry.last_use, name.to_string());
+        self.entries.insert(name.to_string(), entry);
+    }
+
+    fn remove(&mut self, name: &str) -> Option<MemEntry> {
+        let entry = self.entries.remove(name)?;
+        self.lru.remove(&entry.last_use);
+        self.size -= entry.data.0.len() as u64;
+        Some(entry)
+    }
+
+    /// Evict the least recently used entries until they fit in `max_size`.
+    fn evict(&mut self, max_size: u64) {
+        while self.size > max_size {
+            let Some((_, name)) = self.lru.pop_first() else {
+                break;
+            };
+            self.remove(&name);
+        }
+    }
+}
+
+/// A [`Store`] that keeps blobs in memory. By default it grows without bound,
+/// but it can be limited to act as a cache instead.
 #[derive(Debug, Clone)]
 pub struct MemStore {
-    inner: SharedMap<String, SharedVec<u8>>,
+    inner: Arc<Mutex<MemInner>>,
+    max_size: Option<u64>,
 }
 
 impl MemStore {
     pub fn new() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HashMap::new())),
+            inner: Arc::new(Mutex::new(MemInner::default())),
+            max_size: None,
         }
     }
+
+    /// Limit the total size of the blobs, by evicting the least recently used
+    /// ones as new blobs are stored. Blobs larger than the limit aren't kept.
+    pub fn with_max_size(mut self, max_size_bytes: u64) -> Self {
+        self.max_size = Some(max_size_bytes);
+        self
+    }
+
+    /// Store a blob, and return a handle that can read it back even if it
+    /// was evicted straight away.
+    pub(crate) fn insert(&self, name: &str, data: Vec<u8>) -> MemReadHandle {
+        let now = SystemTime::now();
+        let entry = MemEntry {
+            data: SharedVec(Arc::new(data)),
+            created: now,
+            accessed: now,
+            last_use: 0,
+        };
+        let handle = MemReadHandle::new(entry.clone());
+
+        let mut inner = self.inner.lock().unwrap();
+        match self.max_size {
+            // Making room for it would only empty the store.
+            Some(max_size) if entry.data.0.len() as u64 > max_size => {}
+            Some(max_size) => {
+                inner.insert(name, entry);
+                inner.evict(max_size);
+            }
+            None => inner.insert(name, entry),
+        }
+
+        handle
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Store for MemStore {
@@ -42,33 +147,64 @@ impl Store for MemStore {
 
     fn read(&self, name: &str) -> Result<Self::ReadHandle> {
         let mut inner = self.inner.lock().unwrap();
-        let data = inner.get(name).unwrap().clone();
-        Ok(MemReadHandle::new(data))
+        let entry = inner
+            .touch(name)
+            .ok_or_else(|| Error::not_found("file not found"))?;
+
+        Ok(MemReadHandle::new(entry.clone()))
     }
 
     fn write(&self) -> Result<Self::WriteHandle> {
         Ok(MemWriteHandle {
-            files: self.inner.clone(),
+            store: self.clone(),
             data: Vec::new(),
         })
     }
 
     fn contains(&self, name: &str) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.touch(name).is_some())
+    }
+
+    /// The listing is a snapshot, which is small next to the blobs themselves.
+    fn list(&self) -> Result<Blobs> {
         let inner = self.inner.lock().unwrap();
-        Ok(inner.contains_key(name))
+        let blobs = inner
+            .entries
+            .iter()
+            .map(|(name, entry)| {
+                Ok(BlobInfo {
+                    name: name.clone(),
+                    size: entry.data.0.len() as u64,
+                    accessed: entry.accessed,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Box::new(blobs.into_iter()))
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Error::not_found("file not found"))
     }
 }
 
 pub struct MemWriteHandle {
-    files: SharedMap<String, SharedVec<u8>>,
+    store: MemStore,
     data: Vec<u8>,
 }
 
 impl WriteHandle for MemWriteHandle {
     fn seal(self, name: &str) -> Result<()> {
-        let mut inner = self.files.lock().unwrap();
-        let data = SharedVec(Arc::new(self.data));
-        inner.insert(name.to_owned(), data);
+        self.store.insert(name, self.data);
+        Ok(())
+    }
+
+    fn abort(self) -> Result<()> {
         Ok(())
     }
 }
@@ -85,19 +221,28 @@ impl Write for MemWriteHandle {
 
 pub struct MemReadHandle {
     data: Cursor<SharedVec<u8>>,
+    created: SystemTime,
+    accessed: SystemTime,
 }
 
 impl MemReadHandle {
-    pub fn new(data: SharedVec<u8>) -> Self {
+    fn new(entry: MemEntry) -> Self {
         Self {
-            data: Cursor::new(data),
+            data: Cursor::new(entry.data),
+            created: entry.created,
+            accessed: entry.accessed,
         }
     }
 }
 
 impl ReadHandle for MemReadHandle {
-    fn metadata(&self) {
-        todo!()
+    fn metadata(&self) -> Result<BlobMetadata> {
+        Ok(BlobMetadata {
+            size: self.data.get_ref().0.len() as u64,
+            created: self.created,
+            accessed: self.accessed,
+            compressed: false,
+        })
     }
 }
 
@@ -107,6 +252,12 @@ impl Read for MemReadHandle {
     }
 }
 
+impl Seek for MemReadHandle {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -126,4 +277,70 @@ mod test {
         file.read_to_end(&mut read);
         assert_eq!(read, vec![1, 2, 3]);
     }
+
+    #[test]
+    fn test_metadata() {
+        let store = MemStore::new();
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.seal("foo").unwrap();
+        }
+
+        let metadata = store.read("foo").unwrap().metadata().unwrap();
+        assert_eq!(metadata.size, 3);
+        assert!(!metadata.compressed);
+        assert!(metadata.created <= metadata.accessed);
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let store = MemStore::new().with_max_size(6);
+
+        for name in ["foo", "bar"] {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.seal(name).unwrap();
+        }
+
+        // Using "foo" makes "bar" the least recently used.
+        assert!(store.contains("foo").unwrap());
+
+        let mut file = store.write().unwrap();
+        file.write_all(&[4, 5, 6]).unwrap();
+        file.seal("baz").unwrap();
+
+        assert!(store.contains("foo").unwrap());
+        assert!(!store.contains("bar").unwrap());
+        assert!(store.contains("baz").unwrap());
+
+        // Blobs that could never fit aren't kept.
+        let mut file = store.write().unwrap();
+        file.write_all(&[0; 7]).unwrap();
+        file.seal("huge").unwrap();
+        assert!(!store.contains("huge").unwrap());
+        assert!(store.contains("baz").unwrap());
+    }
+
+    #[test]
+    fn test_list_and_delete() {
+        let store = MemStore::new();
+
+        {
+            let mut file = store.write().unwrap();
+            file.write_all(&[1, 2, 3]).unwrap();
+            file.seal("foo").unwrap();
+        }
+
+        let blobs = store.list().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].name, "foo");
+        assert_eq!(blobs[0].size, 3);
+
+        store.delete("foo").unwrap();
+        assert!(!store.contains("foo").unwrap());
+        assert_eq!(store.list().unwrap().count(), 0);
+        assert!(matches!(store.read("foo"), Err(Error::NotFound(_))));
+    }
 }
@@ -1,5 +1,5 @@
 use bytes::BytesMut;
-use common::hash::Hasher;
+use common::hash::{BlobDigest, DigestFunction, Hasher};
 use proto::bazel::exec::Digest;
 use common::{Error, Result};
 use prost::Message;
@@ -10,49 +10,72 @@ use crate::{Store, WriteHandle};
 use crate::tee::TeeWriter;
 
 /// Convenience extensions for using the store as content-addressable storage
-/// for serialized proto messages.
+/// for serialized proto messages. Digests are computed with the given
+/// [`DigestFunction`], which also decides the name that blobs are stored under.
 pub trait ProtoStoreExt {
-    /// Read data identified by a [`Digest`].
-    fn read_digest(&self, digest: &Digest) -> Result<impl Read>;
+    /// Read data identified by a [`Digest`], which is checked to be
+    /// well-formed first.
+    fn read_digest(&self, function: DigestFunction, digest: &Digest) -> Result<impl Read>;
 
     /// Read a proto message identified by a [`Digest`].
-    fn read_message<T>(&self, digest: &Digest) -> Result<T>
+    fn read_message<T>(&self, function: DigestFunction, digest: &Digest) -> Result<T>
     where
         T: Message + Default;
 
     /// Write bytes to a file identified by a [`Digest`] hash.
-    fn write_digest(&self, src: impl Read) -> Result<Digest>;
+    fn write_digest(&self, function: DigestFunction, src: impl Read) -> Result<Digest>;
+
+    /// Write a proto message to a file with the given name. Unlike
+    /// [`ProtoStoreExt::write_digest`], the name is chosen by the caller
+    /// rather than derived from the content.
+    fn write_message<T>(&self, name: &str, message: &T) -> Result<()>
+    where
+        T: Message;
 }
 
 impl<S: Store> ProtoStoreExt for S {
-    fn read_digest(&self, digest: &Digest) -> Result<impl Read> {
-        self.read(&digest.hash)
+    fn read_digest(&self, function: DigestFunction, digest: &Digest) -> Result<impl Read> {
+        let digest = BlobDigest::from_proto(function, digest)?;
+        self.read(&digest.blob_name())
     }
 
-    fn read_message<T>(&self, digest: &Digest) -> Result<T>
+    fn read_message<T>(&self, function: DigestFunction, digest: &Digest) -> Result<T>
     where
         T: Message + Default,
     {
         let mut buf = vec![];
-        let mut reader = self.read_digest(digest)?;
+        let mut reader = self.read_digest(function, digest)?;
         reader.read_to_end(&mut buf).map_err(Error::io)?;
 
         let data = BytesMut::from(buf.as_slice());
         T::decode(data).map_err(Error::boxed)
     }
 
-    fn write_digest(&self, src: impl Read) -> Result<Digest> {
+    fn write_digest(&self, function: DigestFunction, src: impl Read) -> Result<Digest> {
         let mut writer = self.write()?;
-        let mut hasher = Hasher::sha256();
+        let mut hasher = Hasher::new(function);
         let mut tee = TeeWriter::new(&mut writer, &mut hasher);
 
         let mut reader = BufReader::new(src);
         let size_bytes = std::io::copy(&mut reader, &mut tee).map_err(Error::io)?;
         tee.flush().map_err(Error::io)?;
 
-        let hash = hasher.finish().to_string();
-        writer.seal(&hash)?;
+        let hash = hasher.finish();
+        writer.seal(&hash.blob_name())?;
+
+        Ok(Digest {
+            hash: hash.to_string(),
+            size_bytes: size_bytes as i64,
+        })
+    }
 
-        Ok(Digest { hash, size_bytes: size_bytes as i64 })
+    fn write_message<T>(&self, name: &str, message: &T) -> Result<()>
+    where
+        T: Message,
+    {
+        let mut writer = self.write()?;
+        writer.write_all(&message.encode_to_vec()).map_err(Error::io)?;
+        writer.flush().map_err(Error::io)?;
+        writer.seal(name)
     }
 }
\ No newline at end of file
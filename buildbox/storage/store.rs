@@ -1,6 +1,35 @@
 use proto::bazel::exec::Digest;
+use crate::Lease;
 use common::Result;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
+use std::time::SystemTime;
+
+/// Summary of a blob held by a [`Store`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobInfo {
+    pub name: String,
+    /// Space taken up by the blob, which may be less than the size of its
+    /// content if the store compresses it.
+    pub size: u64,
+    /// When the blob was last read or looked up.
+    pub accessed: SystemTime,
+}
+
+/// Metadata about a blob that is being read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobMetadata {
+    /// Size of the content of the blob, once decompressed.
+    pub size: u64,
+    /// When the blob was stored.
+    pub created: SystemTime,
+    /// When the blob was last read or looked up.
+    pub accessed: SystemTime,
+    /// Whether the blob is stored compressed.
+    pub compressed: bool,
+}
+
+/// Lazily lists the blobs in a [`Store`].
+pub type Blobs = Box<dyn Iterator<Item = Result<BlobInfo>> + Send>;
 
 /// A content-addressable data store. Designed for potentially large blobs.
 pub trait Store: Clone + Sync + Send {
@@ -18,15 +47,40 @@ pub trait Store: Clone + Sync + Send {
 
     /// Check that the storage contains this digest.
     fn contains(&self, name: &str) -> Result<bool>;
+
+    /// List the blobs in the store, in no particular order. Files that are
+    /// still being written aren't included, and blobs that are added or
+    /// removed while listing may or may not be.
+    fn list(&self) -> Result<Blobs>;
+
+    /// Remove a blob from the store.
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// Keep these files from being evicted until the [`Lease`] is dropped.
+    /// Stores that never evict anything don't need to track leases.
+    fn lease(&self, names: &[&str]) -> Lease {
+        let _ = names;
+        Lease::default()
+    }
+
+    /// The chunks that a blob is stored as, in order, if the store splits
+    /// blobs up. Each chunk can be read from the store as a blob of its own.
+    fn chunks(&self, name: &str) -> Result<Option<Vec<Digest>>> {
+        let _ = name;
+        Ok(None)
+    }
 }
 
 pub trait WriteHandle: Write {
-    /// Finish writing the file and store it under this name. Will fail if a
-    /// file already exists with this name.
+    /// Finish writing the file and store it under this name. A blob that is
+    /// already stored under this name is replaced.
     fn seal(self, name: &str) -> Result<()>;
+
+    /// Discard everything written so far without storing it.
+    fn abort(self) -> Result<()>;
 }
 
-pub trait ReadHandle: Read {
+pub trait ReadHandle: Read + Seek {
     /// Metadata about the file.
-    fn metadata(&self);
+    fn metadata(&self) -> Result<BlobMetadata>;
 }
\ No newline at end of file
@@ -0,0 +1,230 @@
+use super::mem::{MemReadHandle, MemStore, MemWriteHandle};
+use super::{BlobMetadata, Blobs, Lease, ReadHandle, Store, WriteHandle};
+use common::{Error, Result};
+use proto::bazel::exec::Digest;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Largest blob that is cached in memory by default. Most of the blobs that
+/// are read over and over again (directories, commands, small sources) are
+/// well under this, while large outputs would only push them out.
+pub const DEFAULT_MAX_CACHED_BLOB_BYTES: u64 = 64 * 1024;
+
+/// A [`Store`] that keeps small blobs in a [`MemStore`] in front of a slower
+/// backing store.
+///
+/// Blobs are written to both, so the cache can evict blobs without losing
+/// them. Blobs that are read from the backing store are added to the cache on
+/// the way.
+#[derive(Debug, Clone)]
+pub struct TieredStore<B> {
+    cache: MemStore,
+    backing: B,
+    max_cached_blob_size: u64,
+}
+
+impl<B: Store> TieredStore<B> {
+    /// Create a new [`TieredStore`] that caches the blobs of `backing` in
+    /// `cache`, which should be bounded.
+    pub fn new(cache: MemStore, backing: B) -> Self {
+        Self {
+            cache,
+            backing,
+            max_cached_blob_size: DEFAULT_MAX_CACHED_BLOB_BYTES,
+        }
+    }
+
+    /// Only cache blobs up to this size.
+    pub fn with_max_cached_blob_size(mut self, max_cached_blob_bytes: u64) -> Self {
+        self.max_cached_blob_size = max_cached_blob_bytes;
+        self
+    }
+
+    /// Copy a blob that was read from the backing store into the cache.
+    fn cache_blob(&self, name: &str, mut handle: B::ReadHandle) -> Result<MemReadHandle> {
+        let mut data = vec![];
+        handle.read_to_end(&mut data).map_err(Error::io)?;
+        Ok(self.cache.insert(name, data))
+    }
+}
+
+impl<B: Store> Store for TieredStore<B> {
+    type ReadHandle = TieredReadHandle<B::ReadHandle>;
+
+    type WriteHandle = TieredWriteHandle<B::WriteHandle>;
+
+    fn read(&self, name: &str) -> Result<Self::ReadHandle> {
+        match self.cache.read(name) {
+            Ok(handle) => return Ok(TieredReadHandle::Cache(handle)),
+            Err(Error::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        let handle = self.backing.read(name)?;
+        if handle.metadata()?.size > self.max_cached_blob_size {
+            return Ok(TieredReadHandle::Backing(handle));
+        }
+
+        Ok(TieredReadHandle::Cache(self.cache_blob(name, handle)?))
+    }
+
+    fn write(&self) -> Result<Self::WriteHandle> {
+        Ok(TieredWriteHandle {
+            backing: self.backing.write()?,
+            cache: Some(self.cache.write()?),
+            size: 0,
+            max_cached_blob_size: self.max_cached_blob_size,
+        })
+    }
+
+    fn contains(&self, name: &str) -> Result<bool> {
+        Ok(self.cache.contains(name)? || self.backing.contains(name)?)
+    }
+
+    /// The backing store holds every blob, so it's the only one listed.
+    fn list(&self) -> Result<Blobs> {
+        self.backing.list()
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match self.cache.delete(name) {
+            Ok(()) | Err(Error::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        self.backing.delete(name)
+    }
+
+    /// Evicting blobs from the cache doesn't lose them, so only the backing
+    /// store needs to keep them.
+    fn lease(&self, names: &[&str]) -> Lease {
+        self.backing.lease(names)
+    }
+
+    fn chunks(&self, name: &str) -> Result<Option<Vec<Digest>>> {
+        self.backing.chunks(name)
+    }
+}
+
+pub enum TieredReadHandle<R> {
+    Cache(MemReadHandle),
+    Backing(R),
+}
+
+impl<R: ReadHandle> ReadHandle for TieredReadHandle<R> {
+    fn metadata(&self) -> Result<BlobMetadata> {
+        match self {
+            Self::Cache(handle) => handle.metadata(),
+            Self::Backing(handle) => handle.metadata(),
+        }
+    }
+}
+
+impl<R: Read> Read for TieredReadHandle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Cache(handle) => handle.read(buf),
+            Self::Backing(handle) => handle.read(buf),
+        }
+    }
+}
+
+impl<R: Seek> Seek for TieredReadHandle<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Cache(handle) => handle.seek(pos),
+            Self::Backing(handle) => handle.seek(pos),
+        }
+    }
+}
+
+pub struct TieredWriteHandle<W> {
+    backing: W,
+    /// Copy of the blob for the cache, until it grows too large to cache.
+    cache: Option<MemWriteHandle>,
+    size: u64,
+    max_cached_blob_size: u64,
+}
+
+impl<W: WriteHandle> WriteHandle for TieredWriteHandle<W> {
+    fn seal(self, name: &str) -> Result<()> {
+        self.backing.seal(name)?;
+
+        match self.cache {
+            Some(cache) => cache.seal(name),
+            None => Ok(()),
+        }
+    }
+
+    fn abort(self) -> Result<()> {
+        self.backing.abort()
+    }
+}
+
+impl<W: Write> Write for TieredWriteHandle<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.backing.write(buf)?;
+
+        self.size += written as u64;
+        if self.size > self.max_cached_blob_size {
+            self.cache = None;
+        } else if let Some(cache) = &mut self.cache {
+            cache.write_all(&buf[..written])?;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backing.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(store: &impl Store, name: &str, data: &[u8]) {
+        let mut file = store.write().unwrap();
+        file.write_all(data).unwrap();
+        file.seal(name).unwrap();
+    }
+
+    #[test]
+    fn test_write_through() {
+        let cache = MemStore::new();
+        let backing = MemStore::new();
+        let store = TieredStore::new(cache.clone(), backing.clone()).with_max_cached_blob_size(4);
+
+        write(&store, "small", &[1, 2, 3]);
+        write(&store, "large", &[1, 2, 3, 4, 5]);
+
+        assert!(cache.contains("small").unwrap());
+        assert!(!cache.contains("large").unwrap());
+        assert!(backing.contains("small").unwrap());
+        assert!(backing.contains("large").unwrap());
+
+        let mut read = vec![];
+        store.read("large").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1, 2, 3, 4, 5]);
+        assert!(!cache.contains("large").unwrap());
+    }
+
+    #[test]
+    fn test_read_through() {
+        let cache = MemStore::new();
+        let backing = MemStore::new();
+        let store = TieredStore::new(cache.clone(), backing.clone());
+
+        write(&backing, "foo", &[1, 2, 3]);
+        assert!(!cache.contains("foo").unwrap());
+
+        let mut read = vec![];
+        store.read("foo").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1, 2, 3]);
+        assert!(cache.contains("foo").unwrap());
+
+        store.delete("foo").unwrap();
+        assert!(!cache.contains("foo").unwrap());
+        assert!(!store.contains("foo").unwrap());
+    }
+}
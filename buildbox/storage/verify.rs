@@ -0,0 +1,224 @@
+use super::{BlobMetadata, Blobs, Lease, ReadHandle, Store};
+use common::hash::{self, Hasher};
+use common::Result;
+use proto::bazel::exec::Digest;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// A [`Store`] that checks that blobs still hash to their name as they're
+/// read, so that corrupted data is never handed out. The name of a blob says
+/// which digest function it's checked with.
+///
+/// A read fails when it reaches the end of a blob that doesn't match its
+/// name. Reads that seek can't be checked as they go, so the whole blob is
+/// checked up front the first time they seek somewhere else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyingStore<S> {
+    inner: S,
+}
+
+impl<S: Store> VerifyingStore<S> {
+    /// Create a new [`VerifyingStore`] that checks blobs read from `inner`.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: Store> Store for VerifyingStore<S> {
+    type ReadHandle = VerifyingReadHandle<S::ReadHandle>;
+
+    type WriteHandle = S::WriteHandle;
+
+    fn read(&self, name: &str) -> Result<Self::ReadHandle> {
+        let inner = self.inner.read(name)?;
+        Ok(VerifyingReadHandle::new(inner, name))
+    }
+
+    fn write(&self) -> Result<Self::WriteHandle> {
+        self.inner.write()
+    }
+
+    fn contains(&self, name: &str) -> Result<bool> {
+        self.inner.contains(name)
+    }
+
+    fn list(&self) -> Result<Blobs> {
+        self.inner.list()
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.inner.delete(name)
+    }
+
+    fn lease(&self, names: &[&str]) -> Lease {
+        self.inner.lease(names)
+    }
+
+    fn chunks(&self, name: &str) -> Result<Option<Vec<Digest>>> {
+        self.inner.chunks(name)
+    }
+}
+
+pub struct VerifyingReadHandle<R> {
+    inner: R,
+    name: String,
+    /// Hash of everything read so far, until the blob has been verified.
+    hasher: Option<Hasher>,
+    corrupt: bool,
+}
+
+impl<R: ReadHandle> VerifyingReadHandle<R> {
+    fn new(inner: R, name: &str) -> Self {
+        Self {
+            inner,
+            name: name.to_string(),
+            hasher: Some(Hasher::new(digest_function(name))),
+            corrupt: false,
+        }
+    }
+
+    fn check(&mut self, hasher: Hasher) -> io::Result<()> {
+        let hash = hasher.finish().blob_name();
+        if hash != self.name {
+            tracing::error!("Blob {} is corrupt, its data hashes to {hash}", self.name);
+            self.corrupt = true;
+        }
+
+        self.ensure_not_corrupt()
+    }
+
+    /// Keep failing once the blob is known to be corrupt.
+    fn ensure_not_corrupt(&self) -> io::Result<()> {
+        if self.corrupt {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("blob {} is corrupt", self.name),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: ReadHandle> ReadHandle for VerifyingReadHandle<R> {
+    fn metadata(&self) -> Result<BlobMetadata> {
+        self.inner.metadata()
+    }
+}
+
+impl<R: ReadHandle> Read for VerifyingReadHandle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.ensure_not_corrupt()?;
+        let read = self.inner.read(buf)?;
+
+        if read == 0 && !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                self.check(hasher)?;
+            }
+        } else if let Some(hasher) = &mut self.hasher {
+            hasher.write_all(&buf[..read])?;
+        }
+
+        Ok(read)
+    }
+}
+
+impl<R: ReadHandle> Seek for VerifyingReadHandle<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.ensure_not_corrupt()?;
+
+        if self.hasher.is_some() {
+            // Seeking to where the reader already is doesn't skip anything,
+            // so the blob can still be checked as it's read.
+            let position = self.inner.stream_position()?;
+            if matches!(pos, SeekFrom::Start(to) if to == position) || pos == SeekFrom::Current(0) {
+                return Ok(position);
+            }
+
+            self.inner.rewind()?;
+
+            let mut hasher = Hasher::new(digest_function(&self.name));
+            io::copy(&mut self.inner, &mut hasher)?;
+            self.hasher = None;
+            self.check(hasher)?;
+
+            self.inner.seek(SeekFrom::Start(position))?;
+        }
+
+        self.inner.seek(pos)
+    }
+}
+
+/// The function that a blob was hashed with, going by its name. Blobs that
+/// aren't named after a digest can't match any hash, whichever is used.
+fn digest_function(name: &str) -> hash::DigestFunction {
+    hash::Digest::from_blob_name(name)
+        .map(|digest| digest.function())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mem::MemStore;
+    use crate::WriteHandle;
+
+    fn hash(data: &[u8]) -> String {
+        let mut hasher = Hasher::sha256();
+        hasher.write_all(data).unwrap();
+        hasher.finish().to_string()
+    }
+
+    #[test]
+    fn test_verify() {
+        let store = VerifyingStore::new(MemStore::new());
+        let good = hash(b"good");
+        let bad = hash(b"bad");
+
+        for (name, data) in [(&good, b"good"), (&bad, b"evil")] {
+            let mut file = store.write().unwrap();
+            file.write_all(data).unwrap();
+            file.seal(name).unwrap();
+        }
+
+        let mut read = vec![];
+        store.read(&good).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"good");
+
+        let mut file = store.read(&bad).unwrap();
+        let err = file.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(file.read(&mut [0; 4]).is_err());
+
+        // Seeking checks the whole blob first, unless it stays where it is.
+        let mut file = store.read(&bad).unwrap();
+        assert!(file.seek(SeekFrom::Start(2)).is_err());
+        let mut file = store.read(&bad).unwrap();
+        assert_eq!(file.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert!(file.read_to_end(&mut vec![]).is_err());
+        let mut file = store.read(&good).unwrap();
+        assert_eq!(file.seek(SeekFrom::Start(2)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_verify_digest_functions() {
+        let store = VerifyingStore::new(MemStore::new());
+        let sha256 = hash::compute(hash::DigestFunction::Sha256, b"good");
+        let blake3 = hash::Digest::parse(hash::DigestFunction::Blake3, sha256.as_str()).unwrap();
+        let good = hash::compute(hash::DigestFunction::Blake3, b"good").blob_name();
+
+        for name in [&good, &blake3.blob_name()] {
+            let mut file = store.write().unwrap();
+            file.write_all(b"good").unwrap();
+            file.seal(name).unwrap();
+        }
+
+        let mut read = vec![];
+        store.read(&good).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"good");
+
+        // The SHA256 of the data doesn't match a BLAKE3 name.
+        let mut file = store.read(&blake3.blob_name()).unwrap();
+        assert!(file.read_to_end(&mut vec![]).is_err());
+    }
+}